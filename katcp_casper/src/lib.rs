@@ -1,5 +1,14 @@
+use std::fmt::Display;
+
 use base64::{decode, encode};
-use katcp::prelude::*;
+// tcpborphserver speaks the standard sensor messages, so we use them as-is
+pub use katcp::messages::sensors::{
+    SamplingRequest, SamplingStrategy, SensorList, SensorListInform, SensorReading, SensorSampling,
+    SensorStatus, SensorUpdates, SensorValue,
+};
+// As are the core introspection and control messages, other than ?version-list
+pub use katcp::messages::core::{Halt, Help, Restart, Watchdog};
+use katcp::{messages::core::GenericReply, prelude::*};
 use katcp_derive::{KatcpDiscrete, KatcpMessage};
 
 #[derive(KatcpMessage, Debug, PartialEq, Eq, Clone)]
@@ -20,9 +29,31 @@ pub enum Listbof {
 }
 
 #[derive(KatcpMessage, Debug, PartialEq, Eq, Clone)]
-/// Programs the FPGA with a BOF file that exists on the device
-pub enum Progdev {
+/// Deletes a gateware image stored on the device
+pub enum Delbof {
     Request { filename: String },
+    Reply(GenericReply),
+}
+
+#[derive(KatcpMessage, Debug, PartialEq, Eq, Clone)]
+/// Opens a port on the server to allow us to upload a gateware image, which it stores as `filename`
+/// The reply comes once the upload has finished
+pub enum Saveremote {
+    Request { port: u32, filename: String },
+    Reply(GenericReply),
+}
+
+#[derive(KatcpMessage, Debug, PartialEq, Eq, Clone)]
+/// Opens a port on the server to allow us to upload a raw (.bin) bitstream
+pub enum Uploadbin {
+    Request { port: u32 },
+    Reply(GenericReply),
+}
+
+#[derive(KatcpMessage, Debug, PartialEq, Eq, Clone)]
+/// Programs the FPGA with a BOF file that exists on the device, or deprograms it without one
+pub enum Progdev {
+    Request { filename: Option<String> },
     Reply { ret_code: RetCode },
 }
 
@@ -109,9 +140,111 @@ pub enum BuildState {
     Inform { timestamp: String },
 }
 
+#[derive(KatcpMessage, Debug, PartialEq, Eq, Clone)]
+/// Lists the versions of the server's components
+pub enum VersionList {
+    Request,
+    Inform(VersionListInform),
+    Reply(IntReply),
+}
+
+/// Unlike the core message, the uuid (build state) is optional as tcpborphserver leaves it off some components
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VersionListInform {
+    pub name: String,
+    pub version: String,
+    pub uuid: Option<String>,
+}
+
+impl ToKatcpArguments for VersionListInform {
+    fn to_arguments(&self) -> Vec<String> {
+        let mut args = vec![self.name.to_argument(), self.version.to_argument()];
+        args.extend(self.uuid.iter().map(|uuid| uuid.to_argument()));
+        args
+    }
+}
+
+impl FromKatcpArguments for VersionListInform {
+    type Err = KatcpError;
+
+    fn from_arguments(strings: &mut impl Iterator<Item = String>) -> Result<Self, Self::Err> {
+        Ok(Self {
+            name: String::from_argument(strings.next().ok_or(KatcpError::MissingArgument)?)?,
+            version: String::from_argument(strings.next().ok_or(KatcpError::MissingArgument)?)?,
+            uuid: strings.next().map(String::from_argument).transpose()?,
+        })
+    }
+}
+
+/// A sensor reading, parsed according to the type its sensor was listed with
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypedSensorValue {
+    Integer(i32),
+    Float(f32),
+    Boolean(bool),
+    Timestamp(KatcpTimestamp),
+    Discrete(String),
+    Address(KatcpAddress),
+    String(String),
+}
+
+impl TypedSensorValue {
+    /// Parses the raw `value` of a reading from a sensor of type `ty`
+    pub fn parse(ty: ArgumentType, value: &str) -> Result<Self, KatcpError> {
+        Ok(match ty {
+            ArgumentType::Integer => Self::Integer(i32::from_argument(value)?),
+            ArgumentType::Float => Self::Float(f32::from_argument(value)?),
+            ArgumentType::Boolean => Self::Boolean(bool::from_argument(value)?),
+            ArgumentType::Timestamp => Self::Timestamp(KatcpTimestamp::from_argument(value)?),
+            ArgumentType::Discrete => Self::Discrete(String::from_argument(value)?),
+            ArgumentType::Address => Self::Address(KatcpAddress::from_argument(value)?),
+            ArgumentType::String => Self::String(String::from_argument(value)?),
+        })
+    }
+
+    /// The type of the sensor that produced this value
+    pub fn ty(&self) -> ArgumentType {
+        match self {
+            Self::Integer(_) => ArgumentType::Integer,
+            Self::Float(_) => ArgumentType::Float,
+            Self::Boolean(_) => ArgumentType::Boolean,
+            Self::Timestamp(_) => ArgumentType::Timestamp,
+            Self::Discrete(_) => ArgumentType::Discrete,
+            Self::Address(_) => ArgumentType::Address,
+            Self::String(_) => ArgumentType::String,
+        }
+    }
+}
+
+impl Display for TypedSensorValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integer(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{}", v),
+            Self::Boolean(v) => write!(f, "{}", v),
+            Self::Timestamp(v) => write!(f, "{}", v),
+            Self::Discrete(v) | Self::String(v) => write!(f, "{}", v),
+            Self::Address(v) => write!(f, "{}", v.to_argument()),
+        }
+    }
+}
+
+/// The type of a sensor, from the parameters it was listed with
+pub fn sensor_type(params: &ArgumentVec) -> ArgumentType {
+    match params {
+        ArgumentVec::Integer(_) => ArgumentType::Integer,
+        ArgumentVec::Float(_) => ArgumentType::Float,
+        ArgumentVec::Boolean(_) => ArgumentType::Boolean,
+        ArgumentVec::Timestamp(_) => ArgumentType::Timestamp,
+        ArgumentVec::String(_) => ArgumentType::String,
+        ArgumentVec::Discrete(_) => ArgumentType::Discrete,
+        ArgumentVec::Address(_) => ArgumentType::Address,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use katcp::messages::common::roundtrip_test;
+    use katcp::messages::{common::roundtrip_test, sensors::Status};
 
     use super::*;
 
@@ -124,6 +257,36 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_image_management() {
+        roundtrip_test(Delbof::Request {
+            filename: "old.fpg".to_owned(),
+        });
+        roundtrip_test(Delbof::Reply(GenericReply::Ok));
+        roundtrip_test(Delbof::Reply(GenericReply::Error {
+            ret_code: RetCode::Fail,
+            message: "unable to delete old.fpg".to_owned(),
+        }));
+        roundtrip_test(Saveremote::Request {
+            port: 3000,
+            filename: "grex.fpg".to_owned(),
+        });
+        roundtrip_test(Saveremote::Reply(GenericReply::Ok));
+        roundtrip_test(Uploadbin::Request { port: 3000 });
+        roundtrip_test(Uploadbin::Reply(GenericReply::Ok));
+    }
+
+    #[test]
+    fn test_progdev() {
+        roundtrip_test(Progdev::Request {
+            filename: Some("grex.fpg".to_owned()),
+        });
+        roundtrip_test(Progdev::Request { filename: None });
+        roundtrip_test(Progdev::Reply {
+            ret_code: RetCode::Fail,
+        });
+    }
+
     #[test]
     fn test_read() {
         roundtrip_test(Read::Request {
@@ -136,4 +299,49 @@ mod tests {
             bytes: Base64Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
         });
     }
+
+    #[test]
+    fn test_version_list() {
+        roundtrip_test(VersionList::Request);
+        roundtrip_test(VersionList::Reply(IntReply::Ok { num: 3 }));
+        roundtrip_test(VersionList::Inform(VersionListInform {
+            name: "katcp-library".to_owned(),
+            version: "katcp-2.0".to_owned(),
+            uuid: Some("2021-06-15T10:00:00".to_owned()),
+        }));
+        let inform: Message = "#version-list katcp-protocol 5.0-MI".try_into().unwrap();
+        assert_eq!(
+            VersionList::try_from(inform).unwrap(),
+            VersionList::Inform(VersionListInform {
+                name: "katcp-protocol".to_owned(),
+                version: "5.0-MI".to_owned(),
+                uuid: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_typed_sensor_value() {
+        let status: Message = "#sensor-status 1427043968.954988 1 fpga.temp nominal 45.5"
+            .try_into()
+            .unwrap();
+        let SensorStatus::Inform(updates) = status.try_into().unwrap();
+        let reading = updates.readings.first().unwrap();
+        assert_eq!(reading.status, Status::Nominal);
+        let value = TypedSensorValue::parse(ArgumentType::Float, &reading.value).unwrap();
+        assert_eq!(value, TypedSensorValue::Float(45.5));
+        assert_eq!(value.ty(), ArgumentType::Float);
+        assert_eq!(
+            TypedSensorValue::parse(ArgumentType::Boolean, "1").unwrap(),
+            TypedSensorValue::Boolean(true)
+        );
+        assert!(TypedSensorValue::parse(ArgumentType::Integer, "warm").is_err());
+        assert_eq!(
+            sensor_type(&ArgumentVec::Discrete(vec![
+                "on".to_owned(),
+                "off".to_owned()
+            ])),
+            ArgumentType::Discrete
+        );
+    }
 }
@@ -1,6 +1,10 @@
 //! Routines for interacting with the HMCAD1511 ADC
 //! No support for the HMADC1520
-use packed_struct::prelude::*;
+use std::{collections::HashMap, ops::Range};
+
+use packed_struct::{prelude::*, PackingResult};
+
+use crate::{register_address, utils::RegisterAddress};
 
 // As far as I can tell, we talk to the ADC over "Wishbone".
 // This is exposed to us via more Katcp messages, specifically
@@ -8,6 +12,71 @@ use packed_struct::prelude::*;
 // reading and writing from the ADC's registers by wrapping those
 // in nice rust data structures, and then doing the serde with katcp
 
+/// The name of the wishbone device that controls all the ADCs
+pub const CONTROLLER: &str = "adc16_controller";
+/// The number of HMCAD1511 chips on the SNAP
+pub const NUM_CHIPS: usize = 3;
+/// Chip select mask that addresses every ADC at once
+pub const ALL_CHIPS: u8 = 0b111;
+
+/// The chip select mask of `chip`, or of every chip
+pub fn chip_mask(chip: Option<usize>) -> u8 {
+    chip.map_or(ALL_CHIPS, |c| 1 << c)
+}
+/// Each HMCAD1511 has eight ADC cores, each with its own LVDS lane
+pub const NUM_LANES: usize = 8;
+/// The snapshot RAMs of the ADC controller, one per chip
+pub const SNAPSHOT_RAMS: [&str; NUM_CHIPS] = ["adc16_wb_ram0", "adc16_wb_ram1", "adc16_wb_ram2"];
+/// The number of samples (per lane) the snapshot RAMs hold
+pub const SNAPSHOT_DEPTH: usize = 1024;
+
+// The controller itself is a handful of 32 bit words
+// Word 0 is the "3-wire" interface we bit-bang the ADC's SPI through
+// Word 1 is the control word, where we trigger the snapshot RAMs
+pub const THREE_WIRE_OFFSET: u32 = 0;
+pub const CONTROL_OFFSET: u32 = 4;
+
+// Bits of the 3-wire word. The bottom 8 bits are the active-low chip selects.
+pub const CSN: u32 = 0x0ff;
+pub const SDA: u32 = 0x100;
+pub const SCL: u32 = 0x200;
+/// All chips deselected with the clock and data lines high
+pub const IDLE_3WIRE: u32 = CSN | SDA | SCL;
+/// The bit of the control word that captures new data in the snapshot RAMs
+pub const SNAPSHOT_REQUEST: u32 = 0x1;
+
+#[derive(Debug, Copy, Clone)]
+#[repr(u8)]
+enum AdcAddress {
+    TestPattern = 0x25,
+    CustomPattern1 = 0x26,
+    CustomPattern2 = 0x27,
+    QuadCoarseGain = 0x2A,
+    CoarseGain = 0x2B,
+    ChannelMode = 0x31,
+    GainConfig = 0x33,
+    FineGain12 = 0x34,
+    FineGain34 = 0x35,
+    FineGain56 = 0x36,
+    FineGain78 = 0x37,
+    InputSelect12 = 0x3A,
+    InputSelect34 = 0x3B,
+}
+
+register_address! {AdcAddress,TestPattern}
+register_address! {AdcAddress,CustomPattern1}
+register_address! {AdcAddress,CustomPattern2}
+register_address! {AdcAddress,QuadCoarseGain}
+register_address! {AdcAddress,CoarseGain}
+register_address! {AdcAddress,ChannelMode}
+register_address! {AdcAddress,GainConfig}
+register_address! {AdcAddress,FineGain12}
+register_address! {AdcAddress,FineGain34}
+register_address! {AdcAddress,FineGain56}
+register_address! {AdcAddress,FineGain78}
+register_address! {AdcAddress,InputSelect12}
+register_address! {AdcAddress,InputSelect34}
+
 #[derive(PrimitiveEnum_u8, Clone, Copy, Debug, PartialEq)]
 pub enum QuadChannel {
     Ch1 = 0,
@@ -15,3 +84,481 @@ pub enum QuadChannel {
     Ch3 = 2,
     Ch4 = 3,
 }
+
+impl QuadChannel {
+    pub const ALL: [QuadChannel; 4] = [Self::Ch1, Self::Ch2, Self::Ch3, Self::Ch4];
+
+    /// The two LVDS lanes that carry this channel in four-channel mode
+    pub fn lanes(self) -> [usize; 2] {
+        let first = 2 * self as usize;
+        [first, first + 1]
+    }
+}
+
+#[derive(PrimitiveEnum_u8, Clone, Copy, Debug, PartialEq)]
+pub enum PatternMode {
+    Off = 0b000,
+    SingleCustom = 0b001,
+    DualCustom = 0b010,
+    Ramp = 0b100,
+}
+
+#[derive(PackedStruct, Debug)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "2")]
+pub struct TestPattern {
+    #[packed_field(bits = "4..=6", ty = "enum")]
+    pub mode: PatternMode,
+}
+
+// The custom patterns are 16 bits wide, but in 8 bit mode only the top byte makes it out
+
+#[derive(PackedStruct, Debug)]
+pub struct CustomPattern1 {
+    #[packed_field(endian = "msb")]
+    pub bits: u16,
+}
+
+#[derive(PackedStruct, Debug)]
+pub struct CustomPattern2 {
+    #[packed_field(endian = "msb")]
+    pub bits: u16,
+}
+
+#[derive(PrimitiveEnum_u8, Clone, Copy, Debug, PartialEq)]
+pub enum ChannelNum {
+    Single = 1,
+    Dual = 2,
+    Quad = 4,
+}
+
+impl ChannelNum {
+    /// Checks that `channel` is one of the channels that exist in this mode
+    pub fn check(self, channel: QuadChannel) -> Result<(), String> {
+        if (channel as usize) < self as usize {
+            Ok(())
+        } else {
+            Err(format!(
+                "{:?} doesn't exist when the ADC is in {:?} channel mode",
+                channel, self
+            ))
+        }
+    }
+
+    /// Of the `total` identical per-core settings (input selectors, fine gain branches),
+    /// the ones that are assigned to `channel` in this mode
+    pub fn span(self, channel: QuadChannel, total: usize) -> Range<usize> {
+        let per = total / self as usize;
+        channel as usize * per..(channel as usize + 1) * per
+    }
+}
+
+#[derive(PackedStruct, Debug)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "2")]
+pub struct ChannelMode {
+    #[packed_field(bits = "0..=2", ty = "enum")]
+    pub channel_num: ChannelNum,
+    #[packed_field(bits = "8..=9")]
+    pub clk_divide: Integer<u8, packed_bits::Bits<2>>,
+}
+
+/// Coarse gain of each channel in four-channel mode, in dB
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "2")]
+pub struct QuadCoarseGain {
+    #[packed_field(bits = "0..=3")]
+    pub ch1: Integer<u8, packed_bits::Bits<4>>,
+    #[packed_field(bits = "4..=7")]
+    pub ch2: Integer<u8, packed_bits::Bits<4>>,
+    #[packed_field(bits = "8..=11")]
+    pub ch3: Integer<u8, packed_bits::Bits<4>>,
+    #[packed_field(bits = "12..=15")]
+    pub ch4: Integer<u8, packed_bits::Bits<4>>,
+}
+
+/// Coarse gain of each channel in the dual and single channel modes, in dB
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "2")]
+pub struct CoarseGain {
+    #[packed_field(bits = "0..=3")]
+    pub dual_ch1: Integer<u8, packed_bits::Bits<4>>,
+    #[packed_field(bits = "4..=7")]
+    pub dual_ch2: Integer<u8, packed_bits::Bits<4>>,
+    #[packed_field(bits = "8..=11")]
+    pub single_ch1: Integer<u8, packed_bits::Bits<4>>,
+}
+
+/// The largest coarse gain, in dB
+pub const MAX_COARSE_GAIN: u8 = 12;
+
+#[derive(PackedStruct, Debug)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "2")]
+pub struct GainConfig {
+    /// Interpret the coarse gains as multipliers instead of dB
+    #[packed_field(bits = "0")]
+    pub coarse_gain_x: bool,
+    #[packed_field(bits = "1")]
+    pub fine_gain_en: bool,
+}
+
+/// Fine gain of each of the eight ADC cores (branches), sign-magnitude with the sign in bit 6
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FineGain(pub i8);
+
+/// The largest magnitude of a fine gain
+pub const MAX_FINE_GAIN: i8 = 63;
+
+impl FineGain {
+    fn to_bits(self) -> u8 {
+        if self.0 < 0 {
+            0x40 | self.0.unsigned_abs()
+        } else {
+            self.0 as u8
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        let magnitude = (bits & 0x3f) as i8;
+        Self(if bits & 0x40 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+}
+
+/// Each fine gain register holds the gain of two branches
+macro_rules! fine_gain_register {
+    ($name:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub struct $name(pub [FineGain; 2]);
+
+        impl PackedStruct for $name {
+            type ByteArray = [u8; 2];
+
+            fn pack(&self) -> PackingResult<Self::ByteArray> {
+                Ok([self.0[1].to_bits(), self.0[0].to_bits()])
+            }
+
+            fn unpack(src: &Self::ByteArray) -> PackingResult<Self> {
+                Ok($name([
+                    FineGain::from_bits(src[1]),
+                    FineGain::from_bits(src[0]),
+                ]))
+            }
+        }
+    };
+}
+
+fine_gain_register!(FineGain12);
+fine_gain_register!(FineGain34);
+fine_gain_register!(FineGain56);
+fine_gain_register!(FineGain78);
+
+/// The physical inputs that can be switched into each ADC core
+#[derive(PrimitiveEnum_u8, Clone, Copy, Debug, PartialEq)]
+pub enum AdcInput {
+    In1 = 0b00010,
+    In2 = 0b00100,
+    In3 = 0b01000,
+    In4 = 0b10000,
+}
+
+impl AdcInput {
+    pub const ALL: [AdcInput; 4] = [Self::In1, Self::In2, Self::In3, Self::In4];
+}
+
+/// Each input select register holds the cross-point switch for two of the four ADC "slots"
+macro_rules! input_select_register {
+    ($name:ident) => {
+        #[derive(PackedStruct, Debug, Clone, Copy, PartialEq)]
+        #[packed_struct(bit_numbering = "lsb0", size_bytes = "2")]
+        pub struct $name {
+            #[packed_field(bits = "0..=4", ty = "enum")]
+            pub first: AdcInput,
+            #[packed_field(bits = "8..=12", ty = "enum")]
+            pub second: AdcInput,
+        }
+    };
+}
+
+input_select_register!(InputSelect12);
+input_select_register!(InputSelect34);
+
+/// The registers of the HMCAD1511 are write-only, so we remember the last value we
+/// wrote to each of them (per chip) while we're connected. Anything we haven't written
+/// since is taken to be at its reset value, so read-modify-writes never carry over bits
+/// from a stale copy.
+#[derive(Debug, Default)]
+pub struct Shadow {
+    registers: HashMap<(usize, u8), u16>,
+}
+
+impl Shadow {
+    pub fn set_raw(&mut self, chips: u8, addr: u8, data: u16) {
+        for chip in (0..NUM_CHIPS).filter(|chip| chips & (1 << chip) != 0) {
+            self.registers.insert((chip, addr), data);
+        }
+    }
+
+    /// The channel mode we last put `chip` in
+    pub fn channel_mode(&self, chip: usize) -> Result<ChannelNum, String> {
+        self.get::<ChannelMode>(chip)
+            .map(|mode| mode.channel_num)
+            .ok_or_else(|| {
+                format!(
+                    "The channel mode of ADC {} is unknown, as we haven't set it (with --channels)",
+                    chip
+                )
+            })
+    }
+
+    /// The coarse gain of `channel` of `chip` in the current channel mode, in dB
+    pub fn coarse_gain(&self, chip: usize, channel: QuadChannel) -> Result<u8, String> {
+        let mode = self.channel_mode(chip)?;
+        mode.check(channel)?;
+        Ok(match mode {
+            ChannelNum::Quad => {
+                let gains: QuadCoarseGain = self.get(chip).unwrap_or_default();
+                match channel {
+                    QuadChannel::Ch1 => gains.ch1,
+                    QuadChannel::Ch2 => gains.ch2,
+                    QuadChannel::Ch3 => gains.ch3,
+                    QuadChannel::Ch4 => gains.ch4,
+                }
+            }
+            ChannelNum::Dual => {
+                let gains: CoarseGain = self.get(chip).unwrap_or_default();
+                match channel {
+                    QuadChannel::Ch1 => gains.dual_ch1,
+                    _ => gains.dual_ch2,
+                }
+            }
+            ChannelNum::Single => self.get::<CoarseGain>(chip).unwrap_or_default().single_ch1,
+        }
+        .into())
+    }
+
+    /// The fine gains of all eight branches of `chip`
+    pub fn fine_gains(&self, chip: usize) -> [FineGain; 8] {
+        let pairs = [
+            self.get::<FineGain12>(chip).map(|r| r.0),
+            self.get::<FineGain34>(chip).map(|r| r.0),
+            self.get::<FineGain56>(chip).map(|r| r.0),
+            self.get::<FineGain78>(chip).map(|r| r.0),
+        ];
+        let mut gains = [FineGain(0); 8];
+        for (i, pair) in pairs.iter().enumerate() {
+            gains[2 * i..2 * i + 2].copy_from_slice(&pair.unwrap_or([FineGain(0); 2]));
+        }
+        gains
+    }
+
+    /// The inputs switched into the four slots of `chip`, defaulting to the one-to-one mapping
+    pub fn input_selects(&self, chip: usize) -> [AdcInput; 4] {
+        let first = self.get::<InputSelect12>(chip).unwrap_or(InputSelect12 {
+            first: AdcInput::In1,
+            second: AdcInput::In2,
+        });
+        let second = self.get::<InputSelect34>(chip).unwrap_or(InputSelect34 {
+            first: AdcInput::In3,
+            second: AdcInput::In4,
+        });
+        [first.first, first.second, second.first, second.second]
+    }
+
+    /// Returns the last value we wrote to register `T` of `chip`, if any
+    pub fn get<T>(&self, chip: usize) -> Option<T>
+    where
+        T: PackedStruct<ByteArray = [u8; 2]> + RegisterAddress,
+    {
+        self.registers
+            .get(&(chip, T::address()))
+            .and_then(|data| T::unpack(&data.to_be_bytes()).ok())
+    }
+}
+
+/// One capture of the snapshot RAMs of a single chip, indexed by sample and then by lane
+pub type ChipSnapshot = Vec<[u8; NUM_LANES]>;
+
+/// How many ramp samples in a row must miss before we follow the ramp from where it is instead
+const RAMP_RESYNC_MISSES: u32 = 4;
+
+/// The patterns we can ask the ADC to produce in place of real data
+#[derive(Debug, Clone, Copy)]
+pub enum AdcTest {
+    Ramp,
+    SingleCustom(u8),
+    DualCustom(u8, u8),
+}
+
+impl AdcTest {
+    /// The mode of the test pattern register that produces this pattern
+    pub fn mode(&self) -> PatternMode {
+        match self {
+            AdcTest::Ramp => PatternMode::Ramp,
+            AdcTest::SingleCustom(_) => PatternMode::SingleCustom,
+            AdcTest::DualCustom(_, _) => PatternMode::DualCustom,
+        }
+    }
+
+    /// Counts the bit errors on each lane of a capture that was taken with this test pattern
+    pub fn count_errors(&self, capture: &ChipSnapshot) -> [u32; NUM_LANES] {
+        let mut errors = [0u32; NUM_LANES];
+        for (lane, lane_errors) in errors.iter_mut().enumerate() {
+            let samples = capture.iter().map(|s| s[lane]);
+            *lane_errors = match *self {
+                AdcTest::Ramp => {
+                    let mut errs = 0;
+                    let mut expected: Option<u8> = None;
+                    let mut misses = 0;
+                    for sample in samples {
+                        let want = match expected {
+                            Some(want) => want,
+                            None => sample,
+                        };
+                        if want == sample {
+                            misses = 0;
+                        } else {
+                            errs += (want ^ sample).count_ones();
+                            misses += 1;
+                        }
+                        // Keep counting from what we expected, so one bad word is only counted once,
+                        // unless enough in a row are off that the ramp itself must have slipped
+                        let next = if misses >= RAMP_RESYNC_MISSES {
+                            misses = 0;
+                            sample
+                        } else {
+                            want
+                        };
+                        expected = Some(next.wrapping_add(1));
+                    }
+                    errs
+                }
+                AdcTest::SingleCustom(pat) => samples.map(|s| (s ^ pat).count_ones()).sum(),
+                AdcTest::DualCustom(pat1, pat2) => {
+                    // We don't know which of the two patterns the capture started on
+                    let errs = |first: u8, second: u8| -> u32 {
+                        capture
+                            .iter()
+                            .map(|s| s[lane])
+                            .enumerate()
+                            .map(|(i, s)| {
+                                (s ^ if i % 2 == 0 { first } else { second }).count_ones()
+                            })
+                            .sum()
+                    };
+                    errs(pat1, pat2).min(errs(pat2, pat1))
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// Statistics of the samples of one channel, used to set the levels of the analog chain
+#[derive(Debug, Clone)]
+pub struct ChannelLevels {
+    pub mean: f64,
+    pub rms: f64,
+    /// The fraction of samples that have each bit set, LSB first
+    pub bit_occupancy: [f64; 8],
+    /// The fraction of samples at either end of the ADC's range
+    pub clipping: f64,
+    /// Counts of each sample value, from -128 to 127
+    pub histogram: [u32; 256],
+}
+
+impl ChannelLevels {
+    pub fn from_samples(samples: &[i8]) -> Self {
+        let n = samples.len().max(1) as f64;
+        let mut histogram = [0u32; 256];
+        let mut bits = [0u32; 8];
+        for &sample in samples {
+            histogram[(sample as i16 + 128) as usize] += 1;
+            for (bit, count) in bits.iter_mut().enumerate() {
+                *count += ((sample as u8 >> bit) & 1) as u32;
+            }
+        }
+        let mean = samples.iter().map(|&s| s as f64).sum::<f64>() / n;
+        let rms = (samples.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / n).sqrt();
+        let clipped = histogram[0] + histogram[255];
+        Self {
+            mean,
+            rms,
+            bit_occupancy: bits.map(|count| count as f64 / n),
+            clipping: clipped as f64 / n,
+            histogram,
+        }
+    }
+
+    /// Draws the histogram as one line of `width` block characters
+    pub fn sparkline(&self, width: usize) -> String {
+        const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let per = self.histogram.len().div_ceil(width);
+        let bins: Vec<u32> = self.histogram.chunks(per).map(|c| c.iter().sum()).collect();
+        let max = bins.iter().copied().max().unwrap_or(0).max(1);
+        bins.iter()
+            .map(|&b| BLOCKS[(b as usize * (BLOCKS.len() - 1)).div_ceil(max as usize)])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_errors() {
+        let ramp: ChipSnapshot = (0..=255u8).map(|i| [i; NUM_LANES]).collect();
+        assert_eq!(AdcTest::Ramp.count_errors(&ramp), [0; NUM_LANES]);
+        let mut bad_ramp = ramp.clone();
+        bad_ramp[10][3] ^= 0b100;
+        assert_eq!(AdcTest::Ramp.count_errors(&bad_ramp)[3], 1);
+        // A ramp that jumps is only counted until we resync onto it
+        let slipped: ChipSnapshot = (0..=127u8)
+            .chain(200..=255)
+            .map(|i| [i; NUM_LANES])
+            .collect();
+        let jump = (128u8 ^ 200).count_ones()
+            + (129u8 ^ 201).count_ones()
+            + (130u8 ^ 202).count_ones()
+            + (131u8 ^ 203).count_ones();
+        assert_eq!(AdcTest::Ramp.count_errors(&slipped)[0], jump);
+        let dual: ChipSnapshot = (0..64)
+            .map(|i| [if i % 2 == 0 { 0x55 } else { 0xAA }; NUM_LANES])
+            .collect();
+        assert_eq!(
+            AdcTest::DualCustom(0xAA, 0x55).count_errors(&dual),
+            [0; NUM_LANES]
+        );
+        assert_eq!(
+            AdcTest::SingleCustom(0x55).count_errors(&dual),
+            [256; NUM_LANES]
+        );
+    }
+
+    #[test]
+    fn test_gain_registers() {
+        let gains = FineGain34([FineGain(-5), FineGain(63)]);
+        assert_eq!(gains.pack().unwrap(), [63, 0x45]);
+        assert_eq!(FineGain34::unpack(&gains.pack().unwrap()).unwrap(), gains);
+        assert_eq!(ChannelNum::Quad.span(QuadChannel::Ch3, 8), 4..6);
+        assert_eq!(ChannelNum::Dual.span(QuadChannel::Ch2, 4), 2..4);
+        assert!(ChannelNum::Dual.check(QuadChannel::Ch3).is_err());
+        let mut shadow = Shadow::default();
+        shadow.set_raw(0b101, 0x31, 0x0004);
+        assert_eq!(shadow.channel_mode(2), Ok(ChannelNum::Quad));
+        assert!(shadow.channel_mode(1).is_err());
+    }
+
+    #[test]
+    fn test_levels() {
+        let samples: Vec<i8> = [-128, 127, 0, 1].repeat(4);
+        let levels = ChannelLevels::from_samples(&samples);
+        assert_eq!(levels.mean, 0.0);
+        assert_eq!(levels.clipping, 0.5);
+        assert_eq!(levels.bit_occupancy[7], 0.25);
+        assert_eq!(levels.bit_occupancy[0], 0.5);
+        assert_eq!(levels.sparkline(32).chars().count(), 32);
+    }
+}
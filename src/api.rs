@@ -1,24 +1,41 @@
 //! This module holds the top-level functions for interacting with the connected SNAP
 
-use crate::{tengbe::*, utils::*};
+use crate::{
+    adc::*,
+    args::{ExportArgs, FftCommand, ImageCommand, PlotArgs},
+    clock::{self, ClockSource},
+    config::BoardConfig,
+    dry_run,
+    eq::*,
+    export, fft,
+    lmx::{self, PllSettings},
+    requant::BitSelect,
+    snapshot::*,
+    spectrometer::{self, Spectrometer},
+    sync::{self, Epoch, PpsStatus},
+    tengbe::*,
+    utils::*,
+    xadc,
+};
 use katcp::{
     messages::{core::*, log::*},
     prelude::*,
 };
-use katcp_casper::*;
+// Both katcp's core messages and katcp_casper have a VersionList, and we want the one
+// that accepts tcpborphserver's informs without a uuid
+use katcp_casper::{VersionList, *};
 use packed_struct::{prelude::PackedStruct, PackingError};
 use std::{
     fmt::Debug,
-    net::{Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
 };
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::AsyncWriteExt,
     net::TcpStream,
-    time::{sleep, Duration},
+    time::{sleep, timeout, Duration, Instant},
 };
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 pub async fn make_request<T>(state: &mut State, request: T) -> Result<Vec<T>, String>
 where
@@ -29,51 +46,96 @@ where
     let request_msg = request
         .to_message(None)
         .expect("Could not serialize request to a KATCP message");
-    if request_msg.kind() != MessageKind::Request {
+    trace!(?request, "Sending a request");
+    make_typed_request(state, request_msg).await
+}
+
+/// Like `make_request`, but leaves off the optional arguments at the end of `request` that aren't set.
+/// Those serialize as empty arguments (`\@`), which the server takes literally instead of as absent,
+/// so requests like `?sensor-list` that mean "all of them" without an argument need them dropped.
+pub async fn make_request_without_unset<T>(state: &mut State, request: T) -> Result<Vec<T>, String>
+where
+    T: KatcpMessage + Debug,
+    <T as TryFrom<Message>>::Error: Debug,
+{
+    let request_msg = request
+        .to_message(None)
+        .expect("Could not serialize request to a KATCP message");
+    trace!(?request, "Sending a request");
+    make_typed_request(state, without_unset(request_msg)).await
+}
+
+/// `request` without its trailing empty arguments
+fn without_unset(request: Message) -> Message {
+    let mut args = request.arguments();
+    while args.last().is_some_and(|arg| arg == r"\@") {
+        args.pop();
+    }
+    Message::new(request.kind(), request.name(), request.id(), args)
+        .expect("The arguments were already valid")
+}
+
+/// Sends `request`, deserializing the informs and reply that come back
+async fn make_typed_request<T>(state: &mut State, request: Message) -> Result<Vec<T>, String>
+where
+    T: KatcpMessage + Debug,
+    <T as TryFrom<Message>>::Error: Debug,
+{
+    let mut messages = vec![];
+    for v in make_raw_request(state, request).await? {
+        match v.kind() {
+            MessageKind::Request => unreachable!(),
+            MessageKind::Inform => match v.try_into() {
+                Ok(msg) => messages.push(msg),
+                Err(e) => debug!(?e, "Unexpected message"),
+            },
+            MessageKind::Reply => match v.try_into() {
+                Ok(msg) => messages.push(msg),
+                Err(e) => return Err(format!("Got a reply we couldn't deserialize: {:?}", e)),
+            },
+        }
+    }
+    Ok(messages)
+}
+
+/// Sends `request` as is, returning every inform that came back before the reply, and then the reply
+pub async fn make_raw_request(state: &mut State, request: Message) -> Result<Vec<Message>, String> {
+    if request.kind() != MessageKind::Request {
         return Err("We tried to send a request message that wasn't actually a request".to_owned());
     }
-    trace!(?request, "Sending a request");
-    state
-        .writer
-        .write_all(request_msg.to_string().as_bytes())
+    let writer = match &mut state.writer {
+        Some(writer) => writer,
+        None => {
+            println!("{}", dry_run::describe(&request));
+            return Ok(vec![dry_run::reply(&request)]);
+        }
+    };
+    writer
+        .write_all(request.to_string().as_bytes())
         .await
-        .expect("Error writting bytes to TCP connection");
+        .map_err(|e| format!("Error writing to the server: {}", e))?;
     let mut messages = vec![];
     loop {
         match state.unhandled_incoming_messages.recv().await {
             Some(v) => match v.kind() {
                 MessageKind::Request => unreachable!(),
-                MessageKind::Inform => {
-                    let msg = v.try_into();
-                    match msg {
-                        Ok(msg) => messages.push(msg),
-                        Err(e) => {
-                            debug!(?e, "Unexpected message");
-                            continue;
-                        }
-                    }
-                }
+                MessageKind::Inform => messages.push(v),
                 MessageKind::Reply => {
-                    let msg = v.try_into().expect("Got a Reply we couldn't deserialize");
-                    messages.push(msg);
+                    messages.push(v);
                     break;
                 }
             },
-            None => panic!("The channel we were expecting messages from has been closed"),
+            None => return Err("The connection to the server has been closed".to_owned()),
         }
     }
     Ok(messages)
 }
 
-pub async fn ping(state: &mut State) {
-    match make_request(state, Watchdog::Request).await {
-        Ok(v) => {
-            if let Watchdog::Reply(GenericReply::Ok) = v.get(0).unwrap() {
-                debug!("Got a successful ping!");
-            } else {
-                panic!("Got a bad ping, we're bailing");
-            }
-        }
+/// Checks the server is responding, returning any informs that came before its reply
+/// (which, on a fresh connection, are the ones the server greets us with)
+pub async fn ping(state: &mut State) -> Vec<Message> {
+    match try_ping(state).await {
+        Ok(v) => v,
         Err(e) => {
             println!("{}", e);
             panic!("Ping errored: we're bailing");
@@ -81,6 +143,22 @@ pub async fn ping(state: &mut State) {
     }
 }
 
+/// Like `ping`, but for when the server not answering isn't fatal
+pub async fn try_ping(state: &mut State) -> Result<Vec<Message>, String> {
+    let request = Watchdog::Request
+        .to_message(None)
+        .expect("Could not serialize request to a KATCP message");
+    let mut v = make_raw_request(state, request).await?;
+    let reply = v.pop().expect("There's always a reply");
+    if let Ok(Watchdog::Reply(GenericReply::Ok)) = reply.try_into() {
+        debug!("Got a successful ping!");
+        Ok(v)
+    } else {
+        Err("Got a bad ping".to_owned())
+    }
+}
+
+#[allow(clippy::get_first)]
 pub async fn set_device_log_level(state: &mut State, lvl: Level) {
     match make_request(state, LogLevel::Request { level: lvl }).await {
         Ok(v) => {
@@ -100,25 +178,8 @@ pub async fn set_device_log_level(state: &mut State, lvl: Level) {
 }
 
 pub async fn read(register_name: &str, offset: u32, num_bytes: u32, state: &mut State) -> Vec<u8> {
-    match make_request(
-        state,
-        Read::Request {
-            name: register_name.to_owned(),
-            offset,
-            num_bytes,
-        },
-    )
-    .await
-    {
-        Ok(v) => {
-            if let Read::Reply { ret_code, bytes } = v.get(0).unwrap() {
-                assert_eq!(*ret_code, RetCode::Ok);
-                debug!("Read word successfully!");
-                bytes.0.clone()
-            } else {
-                panic!("Got a bad read response, we're bailing");
-            }
-        }
+    match try_read(register_name, offset, num_bytes, state).await {
+        Ok(bytes) => bytes,
         Err(e) => {
             println!("{}", e);
             panic!("Reading bytes errored: we're bailing");
@@ -126,7 +187,38 @@ pub async fn read(register_name: &str, offset: u32, num_bytes: u32, state: &mut
     }
 }
 
-async fn read_int(register_name: &str, state: &mut State) -> u32 {
+/// Like `read`, but for when we can carry on without the value, like when the board goes away
+pub async fn try_read(
+    register_name: &str,
+    offset: u32,
+    num_bytes: u32,
+    state: &mut State,
+) -> Result<Vec<u8>, String> {
+    let request = Read::Request {
+        name: register_name.to_owned(),
+        offset,
+        num_bytes,
+    };
+    match make_request(state, request).await?.first() {
+        Some(Read::Reply {
+            ret_code: RetCode::Ok,
+            bytes,
+        }) if bytes.0.len() == num_bytes as usize => {
+            debug!("Read word successfully!");
+            Ok(bytes.0.clone())
+        }
+        _ => Err(format!("Got a bad response reading {}", register_name)),
+    }
+}
+
+pub async fn try_read_int(register_name: &str, state: &mut State) -> Result<u32, String> {
+    let bytes = try_read(register_name, 0, 4, state).await?;
+    Ok(u32::from_be_bytes(
+        bytes.try_into().expect("We already read 4 bytes"),
+    ))
+}
+
+pub async fn read_int(register_name: &str, state: &mut State) -> u32 {
     // CASPER registers are big endian
     u32::from_be_bytes(
         read(register_name, 0, 4, state)
@@ -136,14 +228,12 @@ async fn read_int(register_name: &str, state: &mut State) -> u32 {
     )
 }
 
-async fn read_bool(register_name: &str, state: &mut State) -> bool {
-    *read(register_name, 0, 4, state)
-        .await
-        .get(0)
-        .expect("Get 1 byte back from request")
-        == 1
+pub async fn read_bool(register_name: &str, state: &mut State) -> bool {
+    // The flag is the least significant bit, like `write_bool` sets it
+    read_int(register_name, state).await & 1 == 1
 }
 
+#[allow(clippy::get_first)]
 pub async fn write(register_name: &str, offset: u32, bytes: &[u8], state: &mut State) {
     match make_request(
         state,
@@ -170,12 +260,12 @@ pub async fn write(register_name: &str, offset: u32, bytes: &[u8], state: &mut S
     }
 }
 
-async fn write_int(register_name: &str, v: u32, state: &mut State) {
+pub async fn write_int(register_name: &str, v: u32, state: &mut State) {
     // CASPER registers are big endian
     write(register_name, 0, &v.to_be_bytes(), state).await
 }
 
-async fn write_bool(register_name: &str, v: bool, state: &mut State) {
+pub async fn write_bool(register_name: &str, v: bool, state: &mut State) {
     write_int(register_name, v as u32, state).await
 }
 
@@ -190,6 +280,15 @@ where
     T::unpack(&bytes.try_into().expect("We already read N bytes"))
 }
 
+pub async fn try_read_packed<T, const N: usize>(name: &str, state: &mut State) -> Result<T, String>
+where
+    T: PackedStruct<ByteArray = [u8; N]> + RegisterAddress,
+{
+    let bytes = try_read(name, T::address() as u32, N as u32, state).await?;
+    T::unpack(&bytes.try_into().expect("We already read N bytes"))
+        .map_err(|e| format!("Couldn't unpack {}: {}", name, e))
+}
+
 pub async fn write_packed<T, const N: usize>(name: &str, packed: T, state: &mut State)
 where
     T: PackedStruct<ByteArray = [u8; N]> + RegisterAddress,
@@ -205,30 +304,591 @@ where
     .await;
 }
 
+/// Shifts a 24 bit address/data word into the ADCs selected by the `chips` mask over the 3-wire interface
+async fn write_adc_word(chips: u8, addr: u8, data: u16, state: &mut State) {
+    let word = ((addr as u32) << 16) | data as u32;
+    // Chip selects are active low
+    let csn = CSN & !(chips as u32);
+    write(
+        CONTROLLER,
+        THREE_WIRE_OFFSET,
+        &IDLE_3WIRE.to_be_bytes(),
+        state,
+    )
+    .await;
+    // MSB first, the ADC latches on the rising edge of the clock
+    for bit in (0..24).rev() {
+        let sda = if (word >> bit) & 1 == 1 { SDA } else { 0 };
+        write(
+            CONTROLLER,
+            THREE_WIRE_OFFSET,
+            &(csn | sda).to_be_bytes(),
+            state,
+        )
+        .await;
+        write(
+            CONTROLLER,
+            THREE_WIRE_OFFSET,
+            &(csn | sda | SCL).to_be_bytes(),
+            state,
+        )
+        .await;
+    }
+    write(
+        CONTROLLER,
+        THREE_WIRE_OFFSET,
+        &IDLE_3WIRE.to_be_bytes(),
+        state,
+    )
+    .await;
+}
+
+pub async fn write_adc<T>(chips: u8, packed: T, state: &mut State)
+where
+    T: PackedStruct<ByteArray = [u8; 2]> + RegisterAddress,
+{
+    let data = u16::from_be_bytes(
+        packed
+            .pack()
+            .expect("An instance of a packed struct should always pack"),
+    );
+    write_adc_word(chips, T::address(), data, state).await;
+    // Remember what we wrote, as we can't read it back
+    state.adc.set_raw(chips, T::address(), data);
+}
+
+/// Puts the ADCs selected by `chips` in `mode`, interleaving their cores between 1, 2, or 4 channels
+pub async fn set_adc_channel_mode(chips: u8, mode: ChannelNum, state: &mut State) {
+    write_adc(
+        chips,
+        ChannelMode {
+            channel_num: mode,
+            clk_divide: 0.into(),
+        },
+        state,
+    )
+    .await;
+}
+
+/// Sets the coarse gain (in dB) of one channel of one chip, in whichever channel mode it is in
+pub async fn set_adc_coarse_gain(
+    chip: usize,
+    channel: QuadChannel,
+    gain: u8,
+    state: &mut State,
+) -> Result<(), String> {
+    if gain > MAX_COARSE_GAIN {
+        return Err(format!(
+            "Coarse gain must be at most {} dB",
+            MAX_COARSE_GAIN
+        ));
+    }
+    let shadow = &state.adc;
+    let mode = shadow.channel_mode(chip)?;
+    mode.check(channel)?;
+    let mask = 1 << chip;
+    match mode {
+        ChannelNum::Quad => {
+            let mut gains: QuadCoarseGain = shadow.get(chip).unwrap_or_default();
+            match channel {
+                QuadChannel::Ch1 => gains.ch1 = gain.into(),
+                QuadChannel::Ch2 => gains.ch2 = gain.into(),
+                QuadChannel::Ch3 => gains.ch3 = gain.into(),
+                QuadChannel::Ch4 => gains.ch4 = gain.into(),
+            }
+            write_adc(mask, gains, state).await;
+        }
+        ChannelNum::Dual => {
+            let mut gains: CoarseGain = shadow.get(chip).unwrap_or_default();
+            match channel {
+                QuadChannel::Ch1 => gains.dual_ch1 = gain.into(),
+                _ => gains.dual_ch2 = gain.into(),
+            }
+            write_adc(mask, gains, state).await;
+        }
+        ChannelNum::Single => {
+            let mut gains: CoarseGain = shadow.get(chip).unwrap_or_default();
+            gains.single_ch1 = gain.into();
+            write_adc(mask, gains, state).await;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the fine gain of every core (branch) that feeds one channel of one chip
+pub async fn set_adc_fine_gain(
+    chip: usize,
+    channel: QuadChannel,
+    gain: i8,
+    state: &mut State,
+) -> Result<(), String> {
+    if gain.unsigned_abs() > MAX_FINE_GAIN as u8 {
+        return Err(format!("Fine gain must be within ±{}", MAX_FINE_GAIN));
+    }
+    let shadow = &state.adc;
+    let mode = shadow.channel_mode(chip)?;
+    mode.check(channel)?;
+    let mask = 1 << chip;
+    let mut gains = shadow.fine_gains(chip);
+    let branches = mode.span(channel, gains.len());
+    for branch in branches.clone() {
+        gains[branch] = FineGain(gain);
+    }
+    write_adc(
+        mask,
+        GainConfig {
+            coarse_gain_x: false,
+            fine_gain_en: true,
+        },
+        state,
+    )
+    .await;
+    // Only touch the registers that hold the branches we changed
+    for pair in branches.start / 2..branches.end / 2 {
+        let gains = [gains[2 * pair], gains[2 * pair + 1]];
+        match pair {
+            0 => write_adc(mask, FineGain12(gains), state).await,
+            1 => write_adc(mask, FineGain34(gains), state).await,
+            2 => write_adc(mask, FineGain56(gains), state).await,
+            _ => write_adc(mask, FineGain78(gains), state).await,
+        }
+    }
+    Ok(())
+}
+
+/// Switches `input` into every core that feeds one channel of one chip
+pub async fn set_adc_input(
+    chip: usize,
+    channel: QuadChannel,
+    input: AdcInput,
+    state: &mut State,
+) -> Result<(), String> {
+    let shadow = &state.adc;
+    let mode = shadow.channel_mode(chip)?;
+    mode.check(channel)?;
+    let mask = 1 << chip;
+    let mut selects = shadow.input_selects(chip);
+    for slot in mode.span(channel, selects.len()) {
+        selects[slot] = input;
+    }
+    write_adc(
+        mask,
+        InputSelect12 {
+            first: selects[0],
+            second: selects[1],
+        },
+        state,
+    )
+    .await;
+    write_adc(
+        mask,
+        InputSelect34 {
+            first: selects[2],
+            second: selects[3],
+        },
+        state,
+    )
+    .await;
+    Ok(())
+}
+
+/// Captures a new set of samples in the ADC snapshot RAMs and reads them back, one capture per chip
+pub async fn snapshot_adc(state: &mut State) -> Vec<ChipSnapshot> {
+    // The capture is triggered on the rising edge of the request bit
+    write(CONTROLLER, CONTROL_OFFSET, &0u32.to_be_bytes(), state).await;
+    write(
+        CONTROLLER,
+        CONTROL_OFFSET,
+        &SNAPSHOT_REQUEST.to_be_bytes(),
+        state,
+    )
+    .await;
+    write(CONTROLLER, CONTROL_OFFSET, &0u32.to_be_bytes(), state).await;
+    let mut captures = vec![];
+    for ram in SNAPSHOT_RAMS {
+        let bytes = read(ram, 0, (SNAPSHOT_DEPTH * NUM_LANES) as u32, state).await;
+        captures.push(
+            bytes
+                .chunks_exact(NUM_LANES)
+                .map(|c| c.try_into().expect("Chunks are exactly NUM_LANES long"))
+                .collect(),
+        );
+    }
+    captures
+}
+
+/// Lists the names of every register (and BRAM and block) in the running design
+pub async fn list_registers(state: &mut State) -> Vec<String> {
+    match make_request(state, Listdev::Request).await {
+        Ok(v) => v
+            .into_iter()
+            .filter_map(|msg| match msg {
+                Listdev::Inform { register } => Some(register),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Listing registers errored: we're bailing");
+        }
+    }
+}
+
+/// Whether the running design has a register called `name` (which we assume in a dry run)
+pub async fn has_register(name: &str, state: &mut State) -> bool {
+    state.dry_run() || list_registers(state).await.iter().any(|r| r == name)
+}
+
+/// Lists the sensors on the device, returning their details by name
+pub async fn list_sensors(state: &mut State) -> Vec<SensorListInform> {
+    match make_request_without_unset(state, SensorList::Request { name: None }).await {
+        Ok(v) => v
+            .into_iter()
+            .filter_map(|msg| match msg {
+                SensorList::Inform(inform) => Some(inform),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Listing sensors errored: we're bailing");
+        }
+    }
+}
+
+/// Reads the current value of every sensor on the device
+pub async fn read_sensors(state: &mut State) -> Vec<SensorReading> {
+    match make_request_without_unset(state, SensorValue::Request { name: None }).await {
+        Ok(v) => v
+            .into_iter()
+            .flat_map(|msg| match msg {
+                SensorValue::Inform(updates) => updates.readings,
+                _ => vec![],
+            })
+            .collect(),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Reading sensors errored: we're bailing");
+        }
+    }
+}
+
+/// Unloads the design from the FPGA, waiting up to `wait` for the server to say it's down
+pub async fn deprogram_fpga(wait: Duration, state: &mut State) -> Result<(), String> {
+    // Only statuses from after our request count
+    state.fpga_status.borrow_and_update();
+    match make_request_without_unset(state, Progdev::Request { filename: None })
+        .await?
+        .last()
+    {
+        Some(Progdev::Reply {
+            ret_code: RetCode::Ok,
+        }) => (),
+        Some(Progdev::Reply { ret_code }) => {
+            return Err(format!("Deprogramming failed: {}", ret_code.to_argument()))
+        }
+        _ => return Err("Got a bad progdev response".to_owned()),
+    }
+    if !state.dry_run() {
+        let fpga_status = &mut state.fpga_status;
+        let down = timeout(wait, async {
+            while fpga_status.changed().await.is_ok() {
+                if *fpga_status.borrow() == Some(FpgaStatus::Down) {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        if down != Ok(true) {
+            return Err("The server never said the FPGA went down".to_owned());
+        }
+    }
+    // A dry run always looks programmed
+    if !state.dry_run() && fpga_programmed(state).await? {
+        return Err("The FPGA still reports that it's programmed".to_owned());
+    }
+    Ok(())
+}
+
+/// Whether there's a design on the FPGA. tcpborphserver fails `?fpgastatus` (logging that the FPGA
+/// isn't programmed) when there isn't one, and otherwise replies ok once the design's registers are mapped.
+pub async fn fpga_programmed(state: &mut State) -> Result<bool, String> {
+    match make_request(state, Fpgastatus::Request).await?.last() {
+        Some(Fpgastatus::Reply {
+            ret_code: RetCode::Ok,
+        }) => Ok(true),
+        Some(Fpgastatus::Reply {
+            ret_code: RetCode::Fail,
+        }) => Ok(false),
+        _ => Err("Got a bad fpgastatus response".to_owned()),
+    }
+}
+
+/// Halts the device, which won't come back until it's power cycled
+pub async fn halt(state: &mut State) -> Result<(), String> {
+    match make_request(state, Halt::Request).await?.last() {
+        Some(Halt::Reply(GenericReply::Ok)) => Ok(()),
+        Some(Halt::Reply(GenericReply::Error { message, .. })) => {
+            Err(format!("Couldn't halt: {}", message))
+        }
+        _ => Err("Got a bad halt response".to_owned()),
+    }
+}
+
+/// Reboots the device
+pub async fn restart(state: &mut State) -> Result<(), String> {
+    match make_request(state, Restart::Request).await?.last() {
+        Some(Restart::Reply(GenericReply::Ok)) => Ok(()),
+        Some(Restart::Reply(GenericReply::Error { message, .. })) => {
+            Err(format!("Couldn't restart: {}", message))
+        }
+        _ => Err("Got a bad restart response".to_owned()),
+    }
+}
+
+/// Lists the gateware images stored on the device
+pub async fn list_images(state: &mut State) -> Vec<String> {
+    match make_request(state, Listbof::Request).await {
+        Ok(v) => v
+            .into_iter()
+            .filter_map(|msg| match msg {
+                Listbof::Inform { filename } => Some(filename),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Listing images errored: we're bailing");
+        }
+    }
+}
+
+/// Deletes the stored gateware image `name`
+pub async fn delete_image(name: &str, state: &mut State) -> Result<(), String> {
+    let request = Delbof::Request {
+        filename: name.to_owned(),
+    };
+    match make_request(state, request).await?.last() {
+        Some(Delbof::Reply(GenericReply::Ok)) => Ok(()),
+        Some(Delbof::Reply(GenericReply::Error { message, .. })) => {
+            Err(format!("Couldn't delete {}: {}", name, message))
+        }
+        _ => Err("Got a bad delete response".to_owned()),
+    }
+}
+
+/// Uploads the image at `path` to the device's storage as `name` over the upload port `port`,
+/// without programming it
+pub async fn store_image(
+    path: &Path,
+    name: &str,
+    port: u16,
+    state: &mut State,
+) -> Result<(), String> {
+    let request = Saveremote::Request {
+        port: port as u32,
+        filename: name.to_owned(),
+    };
+    match request_upload(state, request, path, port).await?.last() {
+        Some(Saveremote::Reply(GenericReply::Ok)) => (),
+        Some(Saveremote::Reply(GenericReply::Error { message, .. })) => {
+            return Err(format!("Couldn't store {}: {}", name, message))
+        }
+        _ => return Err("Got a bad store response".to_owned()),
+    }
+    if !state.dry_run() && !list_images(state).await.iter().any(|image| image == name) {
+        return Err(format!(
+            "{} wasn't stored, even though the upload worked",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Programs the FPGA with the raw bitstream (.bin) at `path` over the upload port `port`.
+/// Unlike an FPG or BOF, it carries no register map, so the design won't have any registers.
+pub async fn program_bin(path: &Path, port: u16, state: &mut State) -> Result<(), String> {
+    let request = Uploadbin::Request { port: port as u32 };
+    match request_upload(state, request, path, port).await?.last() {
+        Some(Uploadbin::Reply(GenericReply::Ok)) => (),
+        Some(Uploadbin::Reply(GenericReply::Error { message, .. })) => {
+            return Err(format!("Couldn't program {}: {}", path.display(), message))
+        }
+        _ => return Err("Got a bad uploadbin response".to_owned()),
+    }
+    if !state.dry_run() && !fpga_programmed(state).await? {
+        return Err(format!(
+            "The FPGA isn't programmed, even though {} uploaded",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Makes `request`, which opens the upload port `port`, and sends the file at `path` through it,
+/// returning the replies once both are done
+async fn request_upload<T>(
+    state: &mut State,
+    request: T,
+    path: &Path,
+    port: u16,
+) -> Result<Vec<T>, String>
+where
+    T: KatcpMessage + Debug,
+    <T as TryFrom<Message>>::Error: Debug,
+{
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    info!("Uploading {}", path.display());
+    let (reply, sent) = if state.dry_run() {
+        let reply = make_request(state, request).await;
+        println!("upload {} bytes to port {}", contents.len(), port);
+        (reply, Ok(()))
+    } else {
+        // Some requests only reply once the upload is done, so send it while we wait
+        let address = state.address;
+        tokio::join!(
+            make_request(state, request),
+            send_file(address, port, &contents)
+        )
+    };
+    sent?;
+    reply
+}
+
+/// Sends `contents` to the upload port the server opened, retrying until it's listening
+async fn send_file(address: IpAddr, port: u16, contents: &[u8]) -> Result<(), String> {
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(SocketAddr::new(address, port)).await {
+            Ok(stream) => break stream,
+            Err(e) if start.elapsed() > Duration::from_secs(2) => {
+                return Err(format!("Couldn't connect to the upload port: {}", e))
+            }
+            Err(_) => sleep(Duration::from_millis(100)).await,
+        }
+    };
+    stream
+        .write_all(contents)
+        .await
+        .map_err(|e| format!("Error while uploading: {}", e))?;
+    stream
+        .shutdown()
+        .await
+        .map_err(|e| format!("Error closing upload connection: {}", e))
+}
+
+/// Lists the requests the server supports, with their descriptions
+pub async fn list_requests(state: &mut State) -> Vec<(String, String)> {
+    match make_request_without_unset(state, Help::Request { name: None }).await {
+        Ok(v) => v
+            .into_iter()
+            .filter_map(|msg| match msg {
+                Help::Inform { name, description } => Some((name, description)),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Listing requests errored: we're bailing");
+        }
+    }
+}
+
+/// Lists the versions of the server's components
+pub async fn list_versions(state: &mut State) -> Vec<VersionListInform> {
+    match make_request(state, VersionList::Request).await {
+        Ok(v) => v
+            .into_iter()
+            .filter_map(|msg| match msg {
+                VersionList::Inform(inform) => Some(inform),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Listing versions errored: we're bailing");
+        }
+    }
+}
+
+/// Sets how the device reports `sensor` with `#sensor-status` informs
+pub async fn set_sensor_sampling(sensor: &str, strategy: SamplingStrategy, state: &mut State) {
+    let request = SensorSampling::Request(SamplingRequest {
+        names: sensor.to_owned(),
+        strategy: Some(strategy),
+    });
+    match make_request(state, request).await {
+        Ok(v) => debug!(?v, "Set sensor sampling"),
+        Err(e) => {
+            println!("{}", e);
+            panic!("Setting sensor sampling errored: we're bailing");
+        }
+    }
+}
+
+/// Reads back how `core` is set up, and whether it's enabled
+pub async fn read_gbe_settings(core: &str, state: &mut State) -> (GbeSettings, bool) {
+    let mac: MacAddress = read_packed(core, state)
+        .await
+        .expect("MAC read shouldn't fail");
+    let ip: IpAddress = read_packed(core, state)
+        .await
+        .expect("IP read shouldn't fail");
+    let gateway: GatewayAddress = read_packed(core, state)
+        .await
+        .expect("Gateway read shouldn't fail");
+    let netmask: Netmask = read_packed(core, state)
+        .await
+        .expect("Netmask read shouldn't fail");
+    let port: Port = read_packed(core, state)
+        .await
+        .expect("Port read shouldn't fail");
+    let enable: PromiscRstEn = read_packed(core, state)
+        .await
+        .expect("Enable read shouldn't fail");
+    let settings = GbeSettings {
+        mac: Some(mac.0),
+        ip: ip.0,
+        gateway: gateway.0,
+        netmask: netmask.0,
+        port: port.port,
+        dest_ip: read_int(DEST_IP_REGISTER, state).await.into(),
+        dest_port: read_int(DEST_PORT_REGISTER, state).await as u16,
+    };
+    (settings, enable.enable)
+}
+
 //////////////////////////////// Command line subcommands
 
 /// Setups the GbE core for use
-pub async fn config_gbe(core: &str, state: &mut State) {
+pub async fn config_gbe(core: &str, settings: &GbeSettings, state: &mut State) {
     // Disable all the counters for the duration of the setup
     write_bool("tx_en", false, state).await;
     // Configure the MAC address
+    if let Some(mac) = settings.mac {
+        write_packed(core, MacAddress(mac), state).await;
+    }
     // Configure the IP
-    write_packed(core, IpAddress(Ipv4Addr::new(192, 168, 5, 20)), state).await;
+    write_packed(core, IpAddress(settings.ip), state).await;
     // Configure the Port
     write_packed(
         core,
         Port {
             port_mask: 0,
-            port: 6000,
+            port: settings.port,
         },
         state,
     )
     .await;
-    // Configure the gateway
-    write_packed(core, GatewayAddress(Ipv4Addr::new(192, 168, 5, 1)), state).await;
+    // Configure the gateway and netmask
+    write_packed(core, GatewayAddress(settings.gateway), state).await;
+    write_packed(core, Netmask(settings.netmask), state).await;
     // Set the destination IP and Port
-    write_int("dest_ip", Ipv4Addr::new(192, 168, 5, 1).into(), state).await;
-    write_int("dest_port", 6000, state).await;
+    write_int(DEST_IP_REGISTER, settings.dest_ip.into(), state).await;
+    write_int(DEST_PORT_REGISTER, settings.dest_port as u32, state).await;
     // Add the server to the ARP table
     // Set the core's enable
     write_packed(
@@ -280,73 +940,944 @@ pub async fn config_gbe(core: &str, state: &mut State) {
     }
 }
 
-/// Uploads and programs the file given by `path` to the FPGA over the upload port `port`
-pub async fn upload(path: PathBuf, port: u16, state: &mut State) {
-    // Upload the file directly and then try to program
-    debug!("The file we want to program doesn't exist on the device (or we're forcing an upload), upload it instead");
-    info!("Attempting to program: {}", path.display());
-    // Get an upload port
-    match make_request(
+/// Runs the ADCs through their test patterns and reports the bit errors on every channel of every chip
+pub async fn adc_test(pattern1: u8, pattern2: u8, state: &mut State) {
+    // In 8 bit mode, only the top byte of the custom patterns is used
+    write_adc(
+        ALL_CHIPS,
+        CustomPattern1 {
+            bits: (pattern1 as u16) << 8,
+        },
         state,
-        Progremote::Request {
-            port: (port as u32),
+    )
+    .await;
+    write_adc(
+        ALL_CHIPS,
+        CustomPattern2 {
+            bits: (pattern2 as u16) << 8,
         },
+        state,
     )
-    .await
-    {
-        Ok(v) => {
-            // We should have gotten one reply
-            if let Some(Progremote::Reply { ret_code }) = v.get(0) {
-                if *ret_code == RetCode::Ok {
-                    debug!("Upload port set: waiting for data");
-                }
-            } else {
-                panic!("Request for upload failed, see logs");
+    .await;
+    let tests = [
+        AdcTest::Ramp,
+        AdcTest::SingleCustom(pattern1),
+        AdcTest::DualCustom(pattern1, pattern2),
+    ];
+    // Errors indexed by chip, channel, then test
+    let mut errors = [[[0u32; 3]; 4]; NUM_CHIPS];
+    for (i, test) in tests.iter().enumerate() {
+        debug!(?test, "Capturing test pattern");
+        write_adc(ALL_CHIPS, TestPattern { mode: test.mode() }, state).await;
+        for (chip, capture) in snapshot_adc(state).await.iter().enumerate() {
+            let lane_errors = test.count_errors(capture);
+            for channel in QuadChannel::ALL {
+                errors[chip][channel as usize][i] =
+                    channel.lanes().iter().map(|&lane| lane_errors[lane]).sum();
+            }
+        }
+    }
+    // Go back to passing through real data
+    write_adc(
+        ALL_CHIPS,
+        TestPattern {
+            mode: PatternMode::Off,
+        },
+        state,
+    )
+    .await;
+    println!("Chip  Channel  Ramp  Single  Dual");
+    for (chip, channels) in errors.iter().enumerate() {
+        for (channel, errs) in QuadChannel::ALL.iter().zip(channels) {
+            println!(
+                "{:<4}  {:<7}  {:<4}  {:<6}  {:<4}",
+                chip,
+                format!("{:?}", channel),
+                errs[0],
+                errs[1],
+                errs[2]
+            );
+        }
+    }
+    let total: u32 = errors.iter().flatten().flatten().sum();
+    if total == 0 {
+        info!("All ADC lanes passed");
+    } else {
+        warn!(total, "ADC test patterns had bit errors");
+    }
+}
+
+/// Repeatedly captures the ADCs and redraws the levels of every channel, `count` times or forever if zero
+pub async fn adc_levels(interval: Duration, count: usize, state: &mut State) {
+    // Without a channel mode set, show every channel of the default four-channel mode
+    let modes: Vec<_> = (0..NUM_CHIPS)
+        .map(|chip| state.adc.channel_mode(chip).unwrap_or(ChannelNum::Quad))
+        .collect();
+    let mut iteration = 0;
+    loop {
+        let captures = snapshot_adc(state).await;
+        // Clear the screen and go home
+        print!("\x1b[2J\x1b[H");
+        println!("Chip  Channel  Mean     RMS     Clip %  Bits 7..0  Histogram (-128 to 127)");
+        for (chip, capture) in captures.iter().enumerate() {
+            let mode = modes[chip];
+            for channel in QuadChannel::ALL
+                .into_iter()
+                .filter(|c| mode.check(*c).is_ok())
+            {
+                let lanes = mode.span(channel, NUM_LANES);
+                // The cores of a channel are interleaved in time
+                let samples: Vec<i8> = capture
+                    .iter()
+                    .flat_map(|s| s[lanes.clone()].iter().map(|&v| v as i8))
+                    .collect();
+                let levels = ChannelLevels::from_samples(&samples);
+                let bits: String = levels
+                    .bit_occupancy
+                    .iter()
+                    .rev()
+                    .map(|b| char::from_digit((b * 9.0).round() as u32, 10).unwrap_or('?'))
+                    .collect();
+                println!(
+                    "{:<4}  {:<7}  {:<7.2}  {:<6.2}  {:<6.2}  {:<9}  {}",
+                    chip,
+                    format!("{:?}", channel),
+                    levels.mean,
+                    levels.rms,
+                    levels.clipping * 100.0,
+                    bits,
+                    levels.sparkline(32)
+                );
             }
         }
+        iteration += 1;
+        if count != 0 && iteration >= count {
+            break;
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Sets the gains of `channel` on `chip` (or every chip), or prints all the gains if there's nothing to set.
+/// The channel mode is set to `mode` first, if given, as it can't be read back from the ADCs.
+pub async fn adc_gain(
+    chip: Option<usize>,
+    mode: Option<ChannelNum>,
+    channel: Option<QuadChannel>,
+    coarse: Option<u8>,
+    fine: Option<i8>,
+    state: &mut State,
+) {
+    if let Some(mode) = mode {
+        set_adc_channel_mode(chip_mask(chip), mode, state).await;
+    }
+    if coarse.is_none() && fine.is_none() {
+        print_adc_gains(state);
+        return;
+    }
+    let channel = match channel {
+        Some(channel) => channel,
+        None => {
+            error!("Which channel to set the gain of is required");
+            return;
+        }
+    };
+    for chip in chip.map_or(0..NUM_CHIPS, |c| c..c + 1) {
+        if let Some(gain) = coarse {
+            if let Err(e) = set_adc_coarse_gain(chip, channel, gain, state).await {
+                error!(chip, "{}", e);
+            }
+        }
+        if let Some(gain) = fine {
+            if let Err(e) = set_adc_fine_gain(chip, channel, gain, state).await {
+                error!(chip, "{}", e);
+            }
+        }
+    }
+}
+
+/// Switches `input` into `channel` on `chip` (or every chip), or prints the mapping if there's nothing to set.
+/// The channel mode is set to `mode` first, if given, as it can't be read back from the ADCs.
+pub async fn adc_input(
+    chip: Option<usize>,
+    mode: Option<ChannelNum>,
+    channel: Option<QuadChannel>,
+    input: Option<AdcInput>,
+    state: &mut State,
+) {
+    if let Some(mode) = mode {
+        set_adc_channel_mode(chip_mask(chip), mode, state).await;
+    }
+    let (channel, input) = match (channel, input) {
+        (Some(channel), Some(input)) => (channel, input),
+        (_, None) => {
+            print_adc_gains(state);
+            return;
+        }
+        (None, _) => {
+            error!("Which channel to switch the input of is required");
+            return;
+        }
+    };
+    for chip in chip.map_or(0..NUM_CHIPS, |c| c..c + 1) {
+        if let Err(e) = set_adc_input(chip, channel, input, state).await {
+            error!(chip, "{}", e);
+        }
+    }
+}
+
+/// Prints the channel mode, gains and input of every channel of every ADC, as we've set them since connecting
+pub fn print_adc_gains(state: &State) {
+    let shadow = &state.adc;
+    println!("Chip  Channel  Coarse (dB)  Fine         Inputs");
+    for chip in 0..NUM_CHIPS {
+        let mode = match shadow.channel_mode(chip) {
+            Ok(mode) => mode,
+            Err(e) => {
+                println!("{:<4}  {}", chip, e);
+                continue;
+            }
+        };
+        let fine = shadow.fine_gains(chip);
+        let inputs = shadow.input_selects(chip);
+        for channel in QuadChannel::ALL
+            .into_iter()
+            .filter(|c| mode.check(*c).is_ok())
+        {
+            let coarse = shadow
+                .coarse_gain(chip, channel)
+                .expect("We already checked the channel mode");
+            let fine: Vec<_> = fine[mode.span(channel, fine.len())]
+                .iter()
+                .map(|g| g.0)
+                .collect();
+            let inputs: Vec<_> = inputs[mode.span(channel, inputs.len())].to_vec();
+            println!(
+                "{:<4}  {:<7}  {:<11}  {:<11}  {:?}",
+                chip,
+                format!("{:?}", channel),
+                coarse,
+                format!("{:?}", fine),
+                inputs
+            );
+        }
+    }
+}
+
+/// Programs the synthesizer to produce `freq` (MHz) from `reference` (MHz), and checks that it locks
+pub async fn clock_set(freq: f64, reference: f64, power: u8, state: &mut State) {
+    if power > 47 {
+        error!("The output power setting must be at most 47");
+        return;
+    }
+    let settings = match PllSettings::new(freq, reference) {
+        Ok(settings) => settings,
         Err(e) => {
-            println!("{}", e);
-            panic!("Requesting an upload port failed: we're bailing");
+            error!("{}", e);
+            return;
         }
     };
-    info!("Uploading {}", path.display());
-    // Netcat the file over
-    let mut file = File::open(path)
-        .await
-        .expect("Could not open file. Is the path correct?");
-    // Read all the data into a buffer here
-    let mut contents = vec![];
-    file.read_to_end(&mut contents)
-        .await
-        .expect("Couldn't read boffile");
-    let mut upload_stream = TcpStream::connect(SocketAddr::new(state.address, port))
-        .await
-        .expect("Error creating upload connection");
-    upload_stream
-        .write_all(&contents)
-        .await
-        .expect("Error while uploading boffile");
-    // Close stream
-    upload_stream
-        .shutdown()
-        .await
-        .expect("Error closing upload connection");
-    info!("Upload complete, waiting for programming");
-    // Wait ???? until we're good
-    sleep(Duration::from_millis(10000)).await;
-    // Check status
-    match make_request(state, Fpgastatus::Request).await {
-        Ok(v) => {
-            if let Some(Fpgastatus::Reply { ret_code }) = v.get(0) {
-                if *ret_code != RetCode::Ok {
-                    panic!("FPGA Reports it's not good to go, strange");
+    debug!(?settings, "Programming the synthesizer");
+    let actual = settings.frequency(reference);
+    if (actual - freq).abs() > 1e-6 {
+        warn!(
+            "{} MHz can't be synthesized exactly, using {} MHz",
+            freq, actual
+        );
+    }
+    lmx::program(&settings, reference, power, state).await;
+    if lmx::wait_for_lock(Duration::from_secs(1), state).await {
+        info!("Synthesizer locked at {} MHz", actual);
+    } else {
+        warn!("Synthesizer did not lock, is the reference present?");
+    }
+}
+
+/// Prints the frequency the synthesizer is programmed to, and whether it's locked
+pub async fn clock_status(reference: f64, state: &mut State) {
+    let settings = lmx::read_settings(state).await;
+    debug!(?settings, "Read back the synthesizer");
+    println!("Frequency: {} MHz", settings.frequency(reference));
+    println!(
+        "Locked: {}",
+        if lmx::locked(state).await {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+}
+
+/// Switches the ADC sample clock to `source` with the source-select `register`
+pub async fn clock_source(source: ClockSource, register: &str, state: &mut State) {
+    write_bool(register, source.into(), state).await;
+    info!(?source, "Switched the sample clock");
+}
+
+/// Estimates the rate (MHz) of the clock driving the free-running `counter` by sampling it twice, `interval` apart
+pub async fn measure_clock(counter: &str, interval: Duration, state: &mut State) -> f64 {
+    // Time each read from its midpoint, to cancel out most of the round trip
+    let before = Instant::now();
+    let start = read_int(counter, state).await;
+    let t_start = before + before.elapsed() / 2;
+    sleep(interval).await;
+    let before = Instant::now();
+    let end = read_int(counter, state).await;
+    let t_end = before + before.elapsed() / 2;
+    clock::counter_rate(start, end, t_end - t_start)
+}
+
+/// Measures the clock driving `counter` and, if we know what to expect, checks it's within `tolerance` ppm
+pub async fn clock_measure(
+    counter: &str,
+    interval: Duration,
+    expected: Option<f64>,
+    tolerance: f64,
+    state: &mut State,
+) {
+    if let Some(expected) = expected {
+        // The counter can only wrap once between samples for the measurement to make sense
+        if expected * 1e6 * interval.as_secs_f64() >= u32::MAX as f64 {
+            error!("The interval is too long, the counter would wrap more than once");
+            return;
+        }
+    }
+    let rate = measure_clock(counter, interval, state).await;
+    println!("Measured: {:.6} MHz", rate);
+    if let Some(expected) = expected {
+        let error = (rate - expected) / expected * 1e6;
+        println!("Expected: {:.6} MHz ({:+.1} ppm)", expected, error);
+        if error.abs() <= tolerance {
+            println!("PASS");
+        } else {
+            println!("FAIL");
+            warn!("The clock is off by more than {} ppm", tolerance);
+        }
+    }
+}
+
+/// Arms the pipeline on a PPS edge and records the epoch to `output` for converting sequence numbers to time
+pub async fn sync_pps(
+    arm_register: &str,
+    pps_register: &str,
+    sample_rate: f64,
+    samples_per_count: u64,
+    output: &Path,
+    state: &mut State,
+) {
+    let armed_at = match sync::arm(arm_register, pps_register, state).await {
+        Ok(armed_at) => armed_at,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    info!(%armed_at, "Armed on PPS");
+    let epoch = Epoch {
+        armed_at,
+        sample_rate,
+        samples_per_count,
+    };
+    match epoch.save(output) {
+        Ok(_) => info!("Recorded the epoch to {}", output.display()),
+        Err(e) => error!("Couldn't record the epoch to {}: {}", output.display(), e),
+    }
+}
+
+/// Watches the PPS for `seconds` and prints the report as JSON, for alerting
+pub async fn pps_health(
+    pps_register: &str,
+    clocks_register: &str,
+    seconds: usize,
+    state: &mut State,
+) {
+    let report = sync::check_pps(pps_register, clocks_register, seconds, state).await;
+    if report.status == PpsStatus::Healthy {
+        info!("The PPS is healthy");
+    }
+    println!(
+        "{}",
+        serde_json::to_string(&report).expect("The report always serializes")
+    );
+}
+
+/// Sets, prints, or automatically finds the FFT's shift schedule, or prints its overflows
+pub async fn fft_command(command: FftCommand, state: &mut State) {
+    match command {
+        FftCommand::Shift { schedule, stages } => match schedule {
+            Some(schedule) => match schedule.with_stages(stages) {
+                Ok(schedule) => fft::set_shift(schedule, state).await,
+                Err(e) => error!("{}", e),
+            },
+            None => {
+                let schedule = fft::get_shift(stages, state).await;
+                let per_stage: Vec<_> = schedule
+                    .to_stages()
+                    .into_iter()
+                    .map(|s| if s { "1" } else { "0" })
+                    .collect();
+                println!("{} ({})", schedule, per_stage.join(","));
+            }
+        },
+        FftCommand::Overflow { clear } => {
+            println!("{}", fft::overflows(state).await);
+            if clear {
+                fft::clear_overflows(state).await;
+            }
+        }
+        FftCommand::Auto { seconds, stages } => {
+            let schedule = fft::auto_shift(stages, seconds, state).await;
+            println!("{}", schedule);
+        }
+    }
+}
+
+/// Writes `coefficients` to the EQ for every one of `channels` channels, or prints the current ones if there's nothing to write
+pub async fn load_eq(
+    coefficients: Option<&Coefficients>,
+    bram: &str,
+    channels: usize,
+    format: &EqFormat,
+    state: &mut State,
+) {
+    if let Err(e) = format.check() {
+        error!("{}", e);
+        return;
+    }
+    let coefficients = match coefficients {
+        Some(coefficients) => coefficients,
+        None => {
+            for (channel, coeff) in read_coefficients(bram, channels, format, state)
+                .await
+                .iter()
+                .enumerate()
+            {
+                println!("{}\t{}", channel, coeff);
+            }
+            return;
+        }
+    };
+    let result = match coefficients.expand(channels) {
+        Ok(coeffs) => write_coefficients(bram, &coeffs, format, state).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => info!("Loaded the EQ coefficients"),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Brings the board to the state described in the configuration file at `path`, printing what each step did
+pub async fn apply(path: &Path, state: &mut State) {
+    let config = match BoardConfig::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    for (step, result) in config.apply(state).await {
+        match result {
+            Ok(true) => println!("{:<24} changed", step),
+            Ok(false) => println!("{:<24} unchanged", step),
+            Err(e) => {
+                println!("{:<24} failed", step);
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Parts of the names of sensors that report how much space is left on the device
+const STORAGE_SENSORS: [&str; 3] = ["disk", "storage", "free"];
+
+/// The reading, if any, of how much space is left for images
+fn storage_reading(readings: &[SensorReading]) -> Option<&SensorReading> {
+    readings.iter().find(|reading| {
+        let name = reading.name.to_lowercase();
+        STORAGE_SENSORS.iter().any(|part| name.contains(part))
+    })
+}
+
+/// Lists, stores, or deletes the gateware images stored on the device
+pub async fn images(command: ImageCommand, state: &mut State) {
+    match command {
+        ImageCommand::List => {
+            for image in list_images(state).await {
+                println!("{}", image);
+            }
+            // tcpborphserver has no request for its filesystem, so the best we can do is
+            // a sensor that reports it
+            let readings = read_sensors(state).await;
+            match storage_reading(&readings) {
+                Some(reading) => println!("Free space ({}): {}", reading.name, reading.value),
+                None => println!("Free space: unknown, the server doesn't report it"),
+            }
+        }
+        ImageCommand::Store { path, name, port } => {
+            let name = match name.or_else(|| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            }) {
+                Some(name) => name,
+                None => {
+                    error!("Give the image a name to store it under");
+                    return;
+                }
+            };
+            match store_image(&path, &name, port, state).await {
+                Ok(_) => info!("Stored {}", name),
+                Err(e) => error!("{}", e),
+            }
+        }
+        ImageCommand::Delete { names } => {
+            for name in names {
+                match delete_image(&name, state).await {
+                    Ok(_) => info!("Deleted {}", name),
+                    Err(e) => error!("{}", e),
                 }
             }
         }
+    }
+}
+
+/// Deprograms the FPGA, confirming it's down
+pub async fn deprogram(wait: Duration, state: &mut State) {
+    match deprogram_fpga(wait, state).await {
+        Ok(_) => info!("Deprogrammed the FPGA"),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Reboots (or, without `reboot`, halts) the device and waits for the connection to drop.
+/// Given `wait`, we then wait that long for the server to come back after a reboot.
+pub async fn shutdown(reboot: bool, wait: Option<Duration>, state: &mut State) {
+    let result = if reboot {
+        restart(state).await
+    } else {
+        halt(state).await
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        return;
+    }
+    if !state.wait_for_disconnect(Duration::from_secs(10)).await {
+        error!("The server accepted the request, but never closed the connection");
+        return;
+    }
+    info!("The server has gone away");
+    let wait = match wait {
+        Some(wait) if reboot => wait,
+        _ => return,
+    };
+    if !state.dry_run() {
+        if let Err(e) = state.reconnect(wait).await {
+            error!(?e, "The server didn't come back");
+            return;
+        }
+    }
+    state.greeting = match try_ping(state).await {
+        Ok(greeting) => greeting,
         Err(e) => {
-            println!("{}", e);
-            panic!("Requesting the FPGA status failed: we're bailing");
+            error!("The server came back, but didn't answer a ping: {}", e);
+            return;
+        }
+    };
+    set_device_log_level(state, Level::Info).await;
+    info!("The server is back");
+}
+
+/// Prints the versions of the server and the loaded design, and the requests the server supports
+pub async fn info(state: &mut State) {
+    println!("Server:");
+    for VersionListInform {
+        name,
+        version,
+        uuid,
+    } in list_versions(state).await
+    {
+        println!("  {:<24} {} {}", name, version, uuid.unwrap_or_default());
+    }
+    // The server only tells us about the design when we connect
+    println!("Design:");
+    let mut described = false;
+    for msg in &state.greeting {
+        if let Ok(Version::Inform { hash }) = msg.clone().try_into() {
+            println!("  {:<24} {}", "version", hash);
+            described = true;
+        } else if let Ok(BuildState::Inform { timestamp }) = msg.clone().try_into() {
+            println!("  {:<24} {}", "build-state", timestamp);
+            described = true;
+        }
+    }
+    if !described {
+        println!("  The server didn't send the design's version when we connected");
+    }
+    println!("Requests:");
+    for (name, description) in list_requests(state).await {
+        println!("  ?{:<23} {}", name, description);
+    }
+}
+
+/// Sends `request` (a request name, like `?help` or `help`, and its arguments) and prints every inform
+/// and then the reply, returning an exit code for the reply's return code
+pub async fn raw(request: &[String], state: &mut State) -> i32 {
+    let (name, args) = request
+        .split_first()
+        .expect("There's always a request name");
+    let name = name.strip_prefix('?').unwrap_or(name);
+    // Names are a letter followed by letters, digits, and dashes
+    let mut chars = name.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        || !chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        error!("{} isn't a valid request name", name);
+        return 4;
+    }
+    let args: Vec<_> = args.iter().map(|arg| arg.to_argument()).collect();
+    let request = match Message::new(MessageKind::Request, name, None, args) {
+        Ok(request) => request,
+        Err(e) => {
+            error!(?e, "Couldn't build the request");
+            return 4;
+        }
+    };
+    let messages = match make_raw_request(state, request).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("{}", e);
+            return 4;
+        }
+    };
+    for msg in &messages {
+        print!("{}", msg);
+    }
+    let reply = messages.last().expect("There's always a reply");
+    match reply.arguments().first().map(RetCode::from_argument) {
+        Some(Ok(RetCode::Ok)) => 0,
+        Some(Ok(RetCode::Fail)) => 1,
+        Some(Ok(RetCode::Invalid)) => 2,
+        _ => 3,
+    }
+}
+
+/// The ADC sample rate in MHz, from the synthesizer (with the given reference) if it's the clock source.
+/// Returns `None` if the ADCs are clocked externally, as we have no way of knowing the rate,
+/// or if the design doesn't have the registers to tell which clock it's using.
+pub async fn sample_rate(reference: f64, state: &mut State) -> Option<f64> {
+    if !has_register(clock::SOURCE_REGISTER, state).await
+        || !has_register(lmx::CONTROLLER, state).await
+    {
+        return None;
+    }
+    match try_read_int(clock::SOURCE_REGISTER, state).await {
+        Ok(source) if source & 1 == 0 => {}
+        _ => return None,
+    }
+    Some(lmx::read_settings(state).await.frequency(reference))
+}
+
+/// Plots spectra as they're accumulated, or prints the next one if `plot` is `None`
+pub async fn spectrum(
+    spectrometer: &Spectrometer,
+    acc_len: Option<u32>,
+    sample_rate: Option<f64>,
+    reference: f64,
+    timeout: Duration,
+    plot: Option<&PlotArgs>,
+    state: &mut State,
+) {
+    if let Some(acc_len) = acc_len {
+        spectrometer.set_acc_len(acc_len, state).await;
+    }
+    let sample_rate = match sample_rate {
+        Some(sample_rate) => sample_rate,
+        None => match self::sample_rate(reference, state).await {
+            Some(sample_rate) => sample_rate,
+            None => {
+                error!("Can't tell the sample rate (the ADCs are clocked externally or the design has no clock select), so it must be given with --sample-rate");
+                return;
+            }
+        },
+    };
+    match plot {
+        Some(plot) => plot_spectrum(spectrometer, sample_rate, timeout, plot, state).await,
+        None => print_spectrum(spectrometer, sample_rate, timeout, state).await,
+    }
+}
+
+/// Reads the next accumulated spectrum and prints the frequency (MHz) and power of every polarisation in every channel
+pub async fn print_spectrum(
+    spectrometer: &Spectrometer,
+    sample_rate: f64,
+    timeout: Duration,
+    state: &mut State,
+) {
+    let spectrum = match spectrometer.read_next(timeout, state).await {
+        Ok(spectrum) => spectrum,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    debug!(acc_cnt = spectrum.acc_cnt, "Read spectrum");
+    for (channel, freq) in spectrometer.frequencies(sample_rate).iter().enumerate() {
+        let powers: Vec<_> = spectrum
+            .pols
+            .iter()
+            .map(|pol| pol[channel].to_string())
+            .collect();
+        println!("{}\t{}", freq, powers.join("\t"));
+    }
+}
+
+/// Redraws a plot of every polarisation each time a new spectrum is accumulated, marking the strongest peaks
+pub async fn plot_spectrum(
+    spectrometer: &Spectrometer,
+    sample_rate: f64,
+    timeout: Duration,
+    plot: &PlotArgs,
+    state: &mut State,
+) {
+    let freqs = spectrometer.frequencies(sample_rate);
+    let mut iteration = 0;
+    loop {
+        let spectrum = match spectrometer.read_next(timeout, state).await {
+            Ok(spectrum) => spectrum,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        // Clear the screen and go home
+        print!("\x1b[2J\x1b[H");
+        for (pol, powers) in spectrum.pols.iter().enumerate() {
+            let db = spectrometer::to_db(powers);
+            let peaks = spectrometer::find_peaks(&db, plot.peaks);
+            println!("Pol {} (accumulation {})", pol, spectrum.acc_cnt);
+            for line in spectrometer::render_plot(&db, &freqs, &peaks, plot.width, plot.height) {
+                println!("{}", line);
+            }
+            for peak in peaks {
+                println!(
+                    "  Peak: channel {:<5} {:>8.3} MHz {:>7.1} dB",
+                    peak, freqs[peak], db[peak]
+                );
+            }
+            println!();
+        }
+        iteration += 1;
+        if plot.count != 0 && iteration >= plot.count {
+            break;
+        }
+    }
+}
+
+/// Runs the bit selection and prints the RMS and clipping of every channel it ended with
+pub async fn select_bits(bit_select: &BitSelect, state: &mut State) {
+    if let Err(e) = bit_select.format.check().and(bit_select.check()) {
+        error!("{}", e);
+        return;
+    }
+    match bit_select.run(state).await {
+        Ok(stats) => {
+            println!("Channel  RMS     Clip %");
+            for (channel, stats) in stats.iter().enumerate() {
+                println!(
+                    "{:<7}  {:<6.2}  {:.2}",
+                    channel,
+                    stats.rms,
+                    stats.clipping * 100.0
+                );
+            }
+        }
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Prints every XADC reading, warning about any outside `thresholds`
+pub async fn print_xadc(thresholds: &xadc::Thresholds, state: &mut State) {
+    let values = match xadc::read_all(thresholds, state).await {
+        Ok(values) => values,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    for (channel, value) in values {
+        println!(
+            "{:<12} {:.3} {}",
+            format!("{:?}", channel),
+            value,
+            channel.units()
+        );
+    }
+}
+
+/// Prints the value of every sensor, then if `watch` is set, logs their updates every `watch` seconds forever
+pub async fn sensors(watch: Option<f32>, state: &mut State) {
+    let list = list_sensors(state).await;
+    println!(
+        "{:<32}  {:<8}  {:<24}  Description",
+        "Name", "Status", "Value"
+    );
+    for reading in read_sensors(state).await {
+        let details = list.iter().find(|s| s.name == reading.name);
+        // Show the typed value if we know the sensor's type, otherwise whatever we got
+        let value = match details
+            .map(|d| TypedSensorValue::parse(sensor_type(&d.params), &reading.value))
+        {
+            Some(Ok(value)) => value.to_string(),
+            _ => reading.value.clone(),
+        };
+        let units = details.map_or("", |d| d.units.as_str());
+        println!(
+            "{:<32}  {:<8}  {:<24}  {}",
+            reading.name,
+            reading.status.to_argument(),
+            format!("{} {}", value, units).trim_end(),
+            details.map_or("", |d| d.description.as_str())
+        );
+    }
+    if let Some(period) = watch {
+        for sensor in &list {
+            set_sensor_sampling(&sensor.name, SamplingStrategy::Period { period }, state).await;
+        }
+        info!("Watching sensors, press Ctrl-C to stop");
+        // The updates are logged as they arrive by the `sensor-status` handler
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Captures data with `snapshot` and saves it as requested in `export`, or prints every sample
+pub async fn capture_snapshot(
+    snapshot: &Snapshot,
+    options: &ArmOptions,
+    timeout: Duration,
+    export: &ExportArgs,
+    state: &mut State,
+) {
+    let capture = match snapshot.capture(options, timeout, state).await {
+        Ok(capture) => capture,
+        Err(e) => {
+            error!("{}", e);
+            return;
         }
+    };
+    info!(samples = capture.len(), "Captured {}", snapshot.name);
+    if let Some(prefix) = &export.output {
+        for format in &export.formats {
+            match export::export(
+                &capture,
+                &snapshot.name,
+                prefix,
+                *format,
+                export.sample_rate.map(|mhz| mhz * 1e6),
+            ) {
+                Ok(paths) => {
+                    for path in paths {
+                        info!("Wrote {}", path.display());
+                    }
+                }
+                Err(e) => error!(?format, "Couldn't save the capture: {}", e),
+            }
+        }
+        return;
+    }
+    let names: Vec<_> = capture.fields.iter().map(|f| f.name.as_str()).collect();
+    println!("{}", names.join("\t"));
+    for i in 0..capture.len() {
+        let row: Vec<_> = capture.columns.iter().map(|c| c[i].to_string()).collect();
+        println!("{}", row.join("\t"));
+    }
+}
+
+/// Uploads and programs the file given by `path` to the FPGA over the upload port `port`
+pub async fn upload(path: PathBuf, port: u16, state: &mut State) {
+    debug!("The file we want to program doesn't exist on the device (or we're forcing an upload), upload it instead");
+    info!("Attempting to program: {}", path.display());
+    match program(&path, port, state).await {
+        Ok(_) => info!("Programming successful"),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Programs the FPGA with the file at `path` (an FPG, BOF or raw .bin bitstream)
+/// over the upload port `port`
+pub async fn program(path: &Path, port: u16, state: &mut State) -> Result<(), String> {
+    // Raw bitstreams go through their own request
+    if path.extension().is_some_and(|ext| ext == "bin") {
+        return program_bin(path, port, state).await;
+    }
+    match request_upload(state, Progremote::Request { port: port as u32 }, path, port)
+        .await
+        .map_err(|e| format!("Uploading {} failed: {}", path.display(), e))?
+        .first()
+    {
+        Some(Progremote::Reply {
+            ret_code: RetCode::Ok,
+        }) => (),
+        _ => return Err("Request for an upload port failed, see logs".to_owned()),
+    }
+    if !state.dry_run() {
+        info!("Upload complete, waiting for programming");
+        // Wait ???? until we're good
+        sleep(Duration::from_millis(10000)).await;
+    }
+    // Check status
+    match make_request(state, Fpgastatus::Request)
+        .await
+        .map_err(|e| format!("Requesting the FPGA status failed: {}", e))?
+        .first()
+    {
+        Some(Fpgastatus::Reply {
+            ret_code: RetCode::Ok,
+        }) => Ok(()),
+        _ => Err(format!(
+            "The FPGA isn't ready after programming {}",
+            path.display()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_reading() {
+        let reading = |name: &str| SensorReading {
+            name: name.to_owned(),
+            status: katcp::messages::sensors::Status::Nominal,
+            value: "1024".to_owned(),
+        };
+        let readings = [reading("fpga.status"), reading("disk.free")];
+        assert_eq!(storage_reading(&readings).unwrap().name, "disk.free");
+        assert!(storage_reading(&readings[..1]).is_none());
+    }
+
+    #[test]
+    fn test_without_unset() {
+        let request = SensorList::Request { name: None }.to_message(None).unwrap();
+        assert_eq!(request.to_string(), "?sensor-list \\@\n");
+        assert_eq!(without_unset(request).to_string(), "?sensor-list\n");
+        // Set arguments, even empty ones in the middle, are left alone
+        let request = Message::new(
+            MessageKind::Request,
+            "write",
+            None,
+            vec!["gbe0", r"\@", "AAAA"],
+        )
+        .unwrap();
+        assert_eq!(without_unset(request.clone()), request);
+    }
+
+    #[tokio::test]
+    async fn test_deprogram_dry_run() {
+        let mut state = State::dry([127, 0, 0, 1].into(), 7147);
+        assert_eq!(
+            deprogram_fpga(Duration::from_secs(1), &mut state).await,
+            Ok(())
+        );
     }
-    info!("Programming successful");
 }
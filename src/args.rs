@@ -1,23 +1,614 @@
-use std::{net::IpAddr, path::PathBuf};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use mac_address::MacAddress;
+use tokio::time::Duration;
+
+use crate::{
+    adc::{AdcInput, ChannelNum, QuadChannel, NUM_CHIPS},
+    clock::{self, ClockSource},
+    eq::{self, Coefficients, EqFormat},
+    export::Format,
+    fft::{self, ShiftSchedule},
+    lmx,
+    snapshot::{ArmOptions, Field, Snapshot},
+    spectrometer::{self, Spectrometer},
+    sync,
+    tengbe::GbeSettings,
+    xadc::Thresholds,
+};
 
 #[derive(Subcommand, Debug)]
 pub(crate) enum Command {
-    /// Uploads a bitstream (FPG or BOF) file to the SNAP
+    /// Uploads a bitstream file (FPG or BOF, or a raw .bin without any registers) to the SNAP and programs it
     Upload {
         path: PathBuf,
         /// The port to upload data through (separate from the katcp port)
         #[clap(long, default_value_t = 3000)]
         port: u16,
     },
+    /// Manages the gateware images stored on the SNAP
+    Images {
+        #[clap(subcommand)]
+        command: ImageCommand,
+    },
+    /// Unloads the design from the FPGA
+    Deprogram {
+        /// How many seconds to wait for the server to say the FPGA is down
+        #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
+        timeout: Duration,
+    },
     /// Configures the 10GbE Core
     ConfigGBE {
         /// The name of the 10GbE Core to configure (from Simulink)
         core: String,
+        #[clap(flatten)]
+        gbe: GbeArgs,
+    },
+    /// Captures data with a snapshot block and prints or saves it
+    Snapshot {
+        #[clap(flatten)]
+        snapshot: SnapshotArgs,
+        #[clap(flatten)]
+        export: ExportArgs,
+    },
+    /// Configures and monitors the ADC sample clock
+    Clock {
+        #[clap(subcommand)]
+        command: ClockCommand,
+    },
+    /// Arms the pipeline on the next PPS and records the UTC time of that edge
+    Sync {
+        /// The ADC sample rate in MHz
+        #[clap(long, default_value_t = 500.0)]
+        sample_rate: f64,
+        /// The number of ADC samples in each increment of the packet sequence number
+        #[clap(long)]
+        samples_per_count: u64,
+        /// Where to record the epoch, as JSON
+        #[clap(long, default_value = sync::DEFAULT_EPOCH_FILE)]
+        output: PathBuf,
+        /// The register that arms the pipeline (from Simulink)
+        #[clap(long, default_value = sync::ARM_REGISTER)]
+        arm_register: String,
+        /// The register that counts PPS edges (from Simulink)
+        #[clap(long, default_value = sync::PPS_COUNT_REGISTER)]
+        pps_register: String,
+    },
+    /// Watches the PPS for missing pulses and jitter, printing a JSON report
+    Pps {
+        /// How many seconds to watch for
+        #[clap(long, default_value_t = 10)]
+        seconds: usize,
+        /// The register that counts PPS edges (from Simulink)
+        #[clap(long, default_value = sync::PPS_COUNT_REGISTER)]
+        pps_register: String,
+        /// The register holding the FPGA clocks between PPS edges (from Simulink)
+        #[clap(long, default_value = sync::CLOCKS_PER_PPS_REGISTER)]
+        clocks_register: String,
+    },
+    /// Configures the FFT's shift schedule and checks it for overflows
+    Fft {
+        #[clap(subcommand)]
+        command: FftCommand,
+    },
+    /// Loads the per-channel equalization coefficients, or prints them if none are given
+    Eq {
+        /// A gain for every channel, or a .npy or text file with a gain per channel
+        coefficients: Option<Coefficients>,
+        /// The BRAM holding the coefficients (from Simulink)
+        #[clap(long, default_value = eq::DEFAULT_BRAM)]
+        bram: String,
+        #[clap(flatten)]
+        format: EqArgs,
+    },
+    /// Plots the latest accumulated spectrum in the terminal as it updates
+    Spectrum {
+        #[clap(flatten)]
+        spectrometer: SpectrometerArgs,
+        #[clap(flatten)]
+        plot: PlotArgs,
+        /// Set the number of spectra to accumulate first
+        #[clap(long)]
+        acc_len: Option<u32>,
+        /// The ADC sample rate in MHz, for the frequency axis.
+        /// Defaults to the synthesizer's frequency, and is required if the ADCs are clocked externally.
+        #[clap(long)]
+        sample_rate: Option<f64>,
+        /// The frequency of the synthesizer's reference, in MHz, for the default sample rate
+        #[clap(long, default_value_t = lmx::DEFAULT_REFERENCE)]
+        reference: f64,
+        /// How long to wait for a new accumulation, in seconds
+        #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
+        timeout: Duration,
+        /// Print the power in every channel of one spectrum instead of plotting
+        #[clap(long)]
+        print: bool,
+    },
+    /// Scales the EQ coefficients until the requantized output of every channel hits a target RMS
+    Requant {
+        /// The snapshot of the requantized output, with a signed field per sample (e.g. --field re:4:0:signed)
+        #[clap(flatten)]
+        snapshot: SnapshotArgs,
+        /// The RMS to aim for, in output levels (LSBs)
+        #[clap(long, default_value_t = 3.0)]
+        target: f64,
+        /// How far from the target (as a fraction of it) each channel may be
+        #[clap(long, default_value_t = 0.1)]
+        tolerance: f64,
+        /// The most times to adjust the coefficients
+        #[clap(long, default_value_t = 8)]
+        iterations: usize,
+        /// The BRAM holding the EQ coefficients (from Simulink)
+        #[clap(long, default_value = eq::DEFAULT_BRAM)]
+        bram: String,
+        #[clap(flatten)]
+        format: EqArgs,
+    },
+    /// Prints the value of every sensor on the SNAP
+    Sensors {
+        /// Keep logging sensor updates, asking for one every this many seconds
+        #[clap(long)]
+        watch: Option<f32>,
+    },
+    /// Prints the FPGA's die temperature and supply voltages
+    Xadc {
+        #[clap(flatten)]
+        thresholds: ThresholdArgs,
+    },
+    /// Keeps polling the SNAP and serves its health as Prometheus metrics
+    Monitor {
+        /// The address to serve the metrics on
+        #[clap(long)]
+        prometheus: SocketAddr,
+        /// Seconds between polls of the board
+        #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
+        interval: Duration,
+        /// A 10GbE core to report the link and counters of (from Simulink)
+        #[clap(long = "core", multiple_occurrences = true)]
+        cores: Vec<String>,
+        /// Any other register to report the value of (from Simulink)
+        #[clap(long = "register", multiple_occurrences = true)]
+        registers: Vec<String>,
+        /// The register that counts PPS edges (from Simulink)
+        #[clap(long, default_value = sync::PPS_COUNT_REGISTER)]
+        pps_register: String,
+        #[clap(flatten)]
+        thresholds: ThresholdArgs,
+    },
+    /// Brings the board to the state described in a TOML file, reporting what changed
+    Apply {
+        /// The board configuration file
+        config: PathBuf,
+    },
+    /// Prints the versions of the server and the loaded design, and the requests the server supports
+    Info,
+    /// Reboots the SNAP, waiting for its server to go away
+    Reboot {
+        /// How many seconds to wait for the server to come back (and answer a ping), if at all
+        #[clap(long, parse(try_from_str = parse_seconds))]
+        wait: Option<Duration>,
+    },
+    /// Halts the SNAP, waiting for its server to go away
+    Halt,
+    /// Opens an interactive shell for sending raw katcp requests and reading and writing registers
+    Shell,
+    /// Sends a raw katcp request and prints everything that comes back, exiting with 0 for ok,
+    /// 1 for fail, 2 for invalid, 3 for a reply without a return code, and 4 if we couldn't send it
+    Raw {
+        /// The request and its arguments, like ?read gbe0 0 4
+        #[clap(required = true)]
+        request: Vec<String>,
+    },
+    /// Interacts with the HMCAD1511 ADCs
+    Adc {
+        #[clap(subcommand)]
+        command: AdcCommand,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct SnapshotArgs {
+    /// The name of the snapshot block (from Simulink)
+    pub(crate) name: String,
+    /// The fields packed in each word, most significant first, as name:width:bin_pt:type
+    #[clap(
+        long = "field",
+        multiple_occurrences = true,
+        default_value = "data:32:0:unsigned"
+    )]
+    pub(crate) fields: Vec<Field>,
+    /// Trigger immediately instead of waiting on the gateware's trigger
+    #[clap(long)]
+    pub(crate) manual_trigger: bool,
+    /// Capture every clock instead of waiting on the gateware's valid signal
+    #[clap(long)]
+    pub(crate) manual_valid: bool,
+    /// Capture continuously until the trigger, keeping the data from before it
+    #[clap(long)]
+    pub(crate) circular: bool,
+    /// Words to wait after the trigger before capturing
+    #[clap(long)]
+    pub(crate) offset: Option<u32>,
+    /// How long to wait for the capture to finish, in seconds
+    #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
+    pub(crate) timeout: Duration,
+}
+
+impl SnapshotArgs {
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot::new(&self.name, self.fields.clone())
+    }
+
+    pub(crate) fn options(&self) -> ArmOptions {
+        ArmOptions {
+            manual_trigger: self.manual_trigger,
+            manual_valid: self.manual_valid,
+            circular: self.circular,
+            offset: self.offset,
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct ExportArgs {
+    /// Save the capture to files starting with this path instead of printing it
+    #[clap(long)]
+    pub(crate) output: Option<PathBuf>,
+    /// The formats to save the capture in
+    #[clap(
+        long = "format",
+        arg_enum,
+        multiple_occurrences = true,
+        default_value = "npy"
+    )]
+    pub(crate) formats: Vec<Format>,
+    /// The sample rate of the captured data in MHz, recorded alongside raw captures
+    #[clap(long)]
+    pub(crate) sample_rate: Option<f64>,
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct SpectrometerArgs {
+    /// The BRAM (or prefix of the interleaved BRAMs) of each polarisation (from Simulink)
+    #[clap(
+        long = "pol",
+        multiple_occurrences = true,
+        default_values = &["spec0", "spec1"]
+    )]
+    pub(crate) pols: Vec<String>,
+    /// The number of BRAMs each polarisation is interleaved across (2 for _even and _odd)
+    #[clap(long, default_value_t = 2, parse(try_from_str = parse_interleave))]
+    pub(crate) interleave: usize,
+    /// The number of frequency channels
+    #[clap(long, default_value_t = spectrometer::DEFAULT_CHANNELS)]
+    pub(crate) channels: usize,
+    /// The format of each accumulated channel, as name:width:bin_pt:type
+    #[clap(long, default_value = spectrometer::DEFAULT_FIELD)]
+    pub(crate) field: Field,
+}
+
+impl SpectrometerArgs {
+    pub(crate) fn spectrometer(&self) -> Spectrometer {
+        Spectrometer::new(
+            self.pols
+                .iter()
+                .map(|pol| spectrometer::bram_names(pol, self.interleave))
+                .collect(),
+            self.channels,
+            self.field.clone(),
+        )
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct GbeArgs {
+    /// The MAC address of the core, otherwise it's left as is
+    #[clap(long)]
+    pub(crate) mac: Option<MacAddress>,
+    /// The IP address of the core
+    #[clap(long, default_value = "192.168.5.20")]
+    pub(crate) ip: Ipv4Addr,
+    #[clap(long, default_value = "192.168.5.1")]
+    pub(crate) gateway: Ipv4Addr,
+    #[clap(long, default_value = "255.255.255.0")]
+    pub(crate) netmask: Ipv4Addr,
+    /// The port the core sends from and listens on
+    #[clap(long, default_value_t = 6000)]
+    pub(crate) port: u16,
+    /// The IP address to send packets to
+    #[clap(long, default_value = "192.168.5.1")]
+    pub(crate) dest_ip: Ipv4Addr,
+    /// The port to send packets to
+    #[clap(long, default_value_t = 6000)]
+    pub(crate) dest_port: u16,
+}
+
+impl GbeArgs {
+    pub(crate) fn settings(&self) -> GbeSettings {
+        GbeSettings {
+            mac: self.mac,
+            ip: self.ip,
+            gateway: self.gateway,
+            netmask: self.netmask,
+            port: self.port,
+            dest_ip: self.dest_ip,
+            dest_port: self.dest_port,
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct PlotArgs {
+    /// The width of the plot in characters
+    #[clap(long, default_value_t = 128)]
+    pub(crate) width: usize,
+    /// The height of the plot in characters
+    #[clap(long, default_value_t = 16)]
+    pub(crate) height: usize,
+    /// The number of peaks to mark
+    #[clap(long, default_value_t = 5)]
+    pub(crate) peaks: usize,
+    /// The number of spectra to plot before stopping, or zero to keep going
+    #[clap(long, default_value_t = 0)]
+    pub(crate) count: usize,
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct ThresholdArgs {
+    /// The hottest the FPGA should get before we warn, in °C
+    #[clap(long, default_value_t = Thresholds::default().max_temperature)]
+    pub(crate) max_temperature: f64,
+    /// How far (in percent) the supplies may be from nominal before we warn
+    #[clap(long, default_value_t = Thresholds::default().supply_tolerance * 100.0)]
+    pub(crate) supply_tolerance: f64,
+}
+
+impl ThresholdArgs {
+    pub(crate) fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            max_temperature: self.max_temperature,
+            supply_tolerance: self.supply_tolerance / 100.0,
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+pub(crate) struct EqArgs {
+    /// The number of frequency channels
+    #[clap(long, default_value_t = eq::DEFAULT_CHANNELS)]
+    pub(crate) channels: usize,
+    /// The width of each coefficient in bits
+    #[clap(long, default_value_t = EqFormat::default().width)]
+    pub(crate) width: u32,
+    /// The binary point of each coefficient
+    #[clap(long, default_value_t = EqFormat::default().bin_pt)]
+    pub(crate) bin_pt: u32,
+}
+
+impl EqArgs {
+    pub(crate) fn format(&self) -> EqFormat {
+        EqFormat {
+            width: self.width,
+            bin_pt: self.bin_pt,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ImageCommand {
+    /// Lists the stored images and the space left for more, if the server reports it
+    List,
+    /// Uploads an image (FPG or BOF) to storage without programming it
+    Store {
+        path: PathBuf,
+        /// The name to store it under, otherwise the name of the file
+        #[clap(long)]
+        name: Option<String>,
+        /// The port to upload data through (separate from the katcp port)
+        #[clap(long, default_value_t = 3000)]
+        port: u16,
+    },
+    /// Deletes stored images
+    Delete {
+        #[clap(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ClockCommand {
+    /// Programs the LMX2581 synthesizer to produce a sample clock of the given frequency
+    Set {
+        /// The frequency to synthesize, in MHz
+        freq: f64,
+        /// The frequency of the synthesizer's reference, in MHz
+        #[clap(long, default_value_t = lmx::DEFAULT_REFERENCE)]
+        reference: f64,
+        /// The output power setting (0-47)
+        #[clap(long, default_value_t = 15)]
+        power: u8,
+    },
+    /// Prints the frequency the synthesizer is programmed to and whether it's locked
+    Status {
+        /// The frequency of the synthesizer's reference, in MHz
+        #[clap(long, default_value_t = lmx::DEFAULT_REFERENCE)]
+        reference: f64,
+    },
+    /// Selects where the ADCs get their sample clock from
+    Source {
+        #[clap(arg_enum)]
+        source: ClockSource,
+        /// The register that switches the clock source (from Simulink)
+        #[clap(long, default_value = clock::SOURCE_REGISTER)]
+        register: String,
+    },
+    /// Estimates the FPGA clock rate by sampling a free-running counter twice
+    Measure {
+        /// The expected clock rate in MHz, to pass or fail the measurement against
+        #[clap(long)]
+        expected: Option<f64>,
+        /// How far (in ppm) the measurement may be from the expected rate and still pass
+        #[clap(long, default_value_t = 1000.0)]
+        tolerance: f64,
+        /// Seconds between the two samples of the counter
+        #[clap(long, default_value = "1", parse(try_from_str = parse_seconds))]
+        interval: Duration,
+        /// The free-running counter register (from Simulink)
+        #[clap(long, default_value = clock::COUNTER_REGISTER)]
+        register: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum FftCommand {
+    /// Sets the shift schedule, or prints it if none is given
+    Shift {
+        /// A mask (0b1111_1111_1111, 0xfff, 4095) or a per-stage list, first stage first (1,1,0,...)
+        schedule: Option<ShiftSchedule>,
+        /// The number of stages in the FFT
+        #[clap(long, default_value_t = fft::DEFAULT_STAGES, parse(try_from_str = parse_stages))]
+        stages: u32,
+    },
+    /// Prints the number of overflowed spectra since the counter was cleared
+    Overflow {
+        /// Clear the counter after reading it
+        #[clap(long)]
+        clear: bool,
+    },
+    /// Finds the schedule with the fewest shifts that doesn't overflow
+    Auto {
+        /// Seconds of data to check for overflows with each candidate schedule
+        #[clap(long, default_value = "1", parse(try_from_str = parse_seconds))]
+        seconds: Duration,
+        /// The number of stages in the FFT
+        #[clap(long, default_value_t = fft::DEFAULT_STAGES, parse(try_from_str = parse_stages))]
+        stages: u32,
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub(crate) enum AdcCommand {
+    /// Checks every ADC lane for bit errors using the ramp and custom test patterns
+    Test {
+        /// The first custom pattern (used in the single and dual pattern tests)
+        #[clap(long, default_value_t = 0b1010_1010)]
+        pattern1: u8,
+        /// The second custom pattern (used in the dual pattern test)
+        #[clap(long, default_value_t = 0b0101_0101)]
+        pattern2: u8,
+    },
+    /// Continuously prints the mean, RMS, bit occupancy, clipping and histogram of every channel
+    Levels {
+        /// Seconds between captures
+        #[clap(long, default_value = "1", parse(try_from_str = parse_seconds))]
+        interval: Duration,
+        /// Stop after this many captures, or never if zero
+        #[clap(long, default_value_t = 0)]
+        count: usize,
+    },
+    /// Sets how many channels (1, 2, or 4) the ADCs interleave their cores between
+    Mode {
+        #[clap(parse(try_from_str = parse_channel_num))]
+        channels: ChannelNum,
+        /// The ADC chip (0-2) to configure, otherwise all of them
+        #[clap(long, parse(try_from_str = parse_chip))]
+        chip: Option<usize>,
+    },
+    /// Prints the digital gains of every channel, or sets the gain of one
+    Gain {
+        /// The ADC chip (0-2) to configure, otherwise all of them
+        #[clap(long, parse(try_from_str = parse_chip))]
+        chip: Option<usize>,
+        /// The channel mode (1, 2, or 4) to put the ADCs in first, as we can't read it back
+        #[clap(long, parse(try_from_str = parse_channel_num))]
+        channels: Option<ChannelNum>,
+        /// The channel (1-4) to configure
+        #[clap(long, parse(try_from_str = parse_channel))]
+        channel: Option<QuadChannel>,
+        /// Coarse gain, in dB
+        #[clap(long)]
+        coarse: Option<u8>,
+        /// Fine gain, in steps of the ADC's fine gain
+        #[clap(long, allow_hyphen_values = true)]
+        fine: Option<i8>,
+    },
+    /// Prints which inputs feed every channel, or switches the input of one
+    Input {
+        /// The ADC chip (0-2) to configure, otherwise all of them
+        #[clap(long, parse(try_from_str = parse_chip))]
+        chip: Option<usize>,
+        /// The channel mode (1, 2, or 4) to put the ADCs in first, as we can't read it back
+        #[clap(long, parse(try_from_str = parse_channel_num))]
+        channels: Option<ChannelNum>,
+        /// The channel (1-4) to configure
+        #[clap(long, parse(try_from_str = parse_channel))]
+        channel: Option<QuadChannel>,
+        /// The physical input (1-4) to switch into the channel
+        #[clap(long, parse(try_from_str = parse_input))]
+        input: Option<AdcInput>,
+    },
+}
+
+fn parse_interleave(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(interleave) if interleave >= 1 => Ok(interleave),
+        _ => Err("Each polarisation must be spread across at least one BRAM".to_owned()),
+    }
+}
+
+fn parse_stages(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(stages) if (1..=32).contains(&stages) => Ok(stages),
+        _ => Err("The number of stages must be between 1 and 32".to_owned()),
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|_| "Must be a number of seconds")?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err("Must be a non-negative number of seconds".to_owned());
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_chip(s: &str) -> Result<usize, String> {
+    let chip: usize = s.parse().map_err(|_| "Chip must be a number")?;
+    if chip < NUM_CHIPS {
+        Ok(chip)
+    } else {
+        Err(format!("Chip must be between 0 and {}", NUM_CHIPS - 1))
+    }
+}
+
+fn parse_channel(s: &str) -> Result<QuadChannel, String> {
+    let n: usize = s.parse().map_err(|_| "Channel must be a number")?;
+    QuadChannel::ALL
+        .get(n.wrapping_sub(1))
+        .copied()
+        .ok_or_else(|| "Channel must be between 1 and 4".to_owned())
+}
+
+fn parse_input(s: &str) -> Result<AdcInput, String> {
+    let n: usize = s.parse().map_err(|_| "Input must be a number")?;
+    AdcInput::ALL
+        .get(n.wrapping_sub(1))
+        .copied()
+        .ok_or_else(|| "Input must be between 1 and 4".to_owned())
+}
+
+fn parse_channel_num(s: &str) -> Result<ChannelNum, String> {
+    match s {
+        "1" => Ok(ChannelNum::Single),
+        "2" => Ok(ChannelNum::Dual),
+        "4" => Ok(ChannelNum::Quad),
+        _ => Err("The number of channels must be 1, 2, or 4".to_owned()),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
@@ -32,4 +623,7 @@ pub(crate) struct Args {
     /// Print all log messages and debug information
     #[clap(short, long)]
     pub(crate) verbose: bool,
+    /// Print the requests we would send (with synthetic replies) instead of connecting to the SNAP
+    #[clap(long)]
+    pub(crate) dry_run: bool,
 }
@@ -0,0 +1,46 @@
+//! Routines for the ADC sample clock that don't depend on where it comes from
+use clap::ArgEnum;
+use serde::Deserialize;
+use tokio::time::Duration;
+
+/// The register that switches the sample clock between the synthesizer and the external input
+pub const SOURCE_REGISTER: &str = "clk_sel";
+/// The free-running counter clocked by the FPGA's fabric (ADC) clock
+pub const COUNTER_REGISTER: &str = "sys_clkcounter";
+
+/// Where the ADCs get their sample clock from
+#[derive(ArgEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockSource {
+    /// The on-board LMX2581 synthesizer
+    Internal,
+    /// The external clock input
+    External,
+}
+
+impl From<ClockSource> for bool {
+    /// The value of the source register that selects `source`
+    fn from(source: ClockSource) -> Self {
+        source == ClockSource::External
+    }
+}
+
+/// The rate (in MHz) of a 32 bit counter that went from `start` to `end` in `elapsed`,
+/// assuming it wrapped at most once
+pub fn counter_rate(start: u32, end: u32, elapsed: Duration) -> f64 {
+    end.wrapping_sub(start) as f64 / elapsed.as_secs_f64() / 1e6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_rate() {
+        let rate = counter_rate(100, 250_000_100, Duration::from_secs(1));
+        assert!((rate - 250.0).abs() < 1e-9);
+        // 250 MHz wraps the counter in about 17 seconds
+        let rate = counter_rate(u32::MAX - 99, 124_999_900, Duration::from_millis(500));
+        assert!((rate - 250.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,430 @@
+//! Declarative board configuration, read from a TOML file
+//!
+//! The file describes the whole state we want the board in, every section of which is optional:
+//!
+//! ```toml
+//! bitstream = { path = "grex.fpg" }
+//! clock = { source = "internal", frequency = 500.0 }
+//! adc = { channels = 2 }
+//! registers = { acc_len = 8192 }
+//! eq = { coefficients = "eq.npy" }
+//!
+//! [[gbe]]
+//! core = "gbe0"
+//! ip = "192.168.5.20"
+//! gateway = "192.168.5.1"
+//! port = 6000
+//! dest_ip = "192.168.5.1"
+//! dest_port = 6000
+//! ```
+//!
+//! Applying it walks through the bring-up steps in that order, only touching the parts of the
+//! board that differ from the file. Relative paths are relative to the file itself.
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use tokio::time::Duration;
+
+use crate::{
+    adc::{ChannelNum, ALL_CHIPS, NUM_CHIPS},
+    api::{
+        config_gbe, fpga_programmed, has_register, list_registers, program, read_bool,
+        read_gbe_settings, read_int, set_adc_channel_mode, write_bool, write_int,
+    },
+    clock::{self, ClockSource},
+    eq::{self, read_coefficients, write_coefficients, Coefficients, EqFormat},
+    lmx::{self, PllSettings},
+    tengbe::GbeSettings,
+    utils::State,
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BitstreamConfig {
+    pub path: PathBuf,
+    /// The port to upload through
+    #[serde(default = "default_upload_port")]
+    pub port: u16,
+    /// Program even if the loaded design has the same registers, like after a rebuild that only changed logic
+    #[serde(default)]
+    pub force: bool,
+}
+
+fn default_upload_port() -> u16 {
+    3000
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClockConfig {
+    pub source: Option<ClockSource>,
+    /// The frequency to program the synthesizer to, in MHz
+    pub frequency: Option<f64>,
+    /// The synthesizer's reference, in MHz
+    #[serde(default = "default_reference")]
+    pub reference: f64,
+    /// The synthesizer's output power setting
+    #[serde(default = "default_power")]
+    pub power: u8,
+}
+
+fn default_reference() -> f64 {
+    lmx::DEFAULT_REFERENCE
+}
+
+fn default_power() -> u8 {
+    15
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdcConfig {
+    /// How many channels (1, 2, or 4) every ADC interleaves its cores between
+    pub channels: u8,
+}
+
+impl AdcConfig {
+    fn mode(&self) -> Result<ChannelNum, String> {
+        match self.channels {
+            1 => Ok(ChannelNum::Single),
+            2 => Ok(ChannelNum::Dual),
+            4 => Ok(ChannelNum::Quad),
+            _ => Err("The number of ADC channels must be 1, 2, or 4".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GbeConfig {
+    /// The name of the 10GbE Core (from Simulink)
+    pub core: String,
+    #[serde(flatten)]
+    pub settings: GbeSettings,
+}
+
+/// Where the EQ coefficients come from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum CoefficientSource {
+    Scalar(f64),
+    Array(Vec<f64>),
+    /// A `.npy` or text file, as `snapctl eq` takes
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EqConfig {
+    pub coefficients: CoefficientSource,
+    #[serde(default = "default_bram")]
+    pub bram: String,
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_bin_pt")]
+    pub bin_pt: u32,
+}
+
+fn default_bram() -> String {
+    eq::DEFAULT_BRAM.to_owned()
+}
+
+fn default_channels() -> usize {
+    eq::DEFAULT_CHANNELS
+}
+
+fn default_width() -> u32 {
+    EqFormat::default().width
+}
+
+fn default_bin_pt() -> u32 {
+    EqFormat::default().bin_pt
+}
+
+impl EqConfig {
+    fn format(&self) -> EqFormat {
+        EqFormat {
+            width: self.width,
+            bin_pt: self.bin_pt,
+        }
+    }
+
+    fn coefficients(&self) -> Result<Vec<f64>, String> {
+        match &self.coefficients {
+            CoefficientSource::Scalar(coeff) => Coefficients::Scalar(*coeff),
+            CoefficientSource::Array(coeffs) => Coefficients::Array(coeffs.clone()),
+            CoefficientSource::File(path) => Coefficients::load(path)?,
+        }
+        .expand(self.channels)
+    }
+}
+
+/// The state we want the board in
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardConfig {
+    pub bitstream: Option<BitstreamConfig>,
+    pub clock: Option<ClockConfig>,
+    pub adc: Option<AdcConfig>,
+    #[serde(default)]
+    pub gbe: Vec<GbeConfig>,
+    /// Values to write to software registers, by name
+    #[serde(default)]
+    pub registers: BTreeMap<String, u32>,
+    pub eq: Option<EqConfig>,
+}
+
+/// The outcome of one step: whether it had to change anything, or why it failed
+pub type StepResult = Result<bool, String>;
+
+impl BoardConfig {
+    /// Parses and checks a configuration, resolving relative paths against `dir`
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut config: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        if let Some(bitstream) = &mut config.bitstream {
+            bitstream.path = dir.join(&bitstream.path);
+        }
+        if let Some(EqConfig {
+            coefficients: CoefficientSource::File(path),
+            ..
+        }) = &mut config.eq
+        {
+            *path = dir.join(&path);
+        }
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Loads the configuration file at `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text, path.parent().unwrap_or_else(|| Path::new(".")))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Catches what we can before touching the board
+    fn check(&self) -> Result<(), String> {
+        if let Some(ClockConfig {
+            frequency: Some(freq),
+            reference,
+            power,
+            ..
+        }) = &self.clock
+        {
+            PllSettings::new(*freq, *reference)?;
+            if *power > 47 {
+                return Err("The output power setting must be at most 47".to_owned());
+            }
+        }
+        if let Some(adc) = &self.adc {
+            adc.mode()?;
+        }
+        if let Some(eq) = &self.eq {
+            eq.format().check()?;
+        }
+        Ok(())
+    }
+
+    /// Performs every step in order, stopping at the first one that fails, and returns
+    /// the name and outcome of each step we got to
+    pub async fn apply(&self, state: &mut State) -> Vec<(String, StepResult)> {
+        let mut steps = vec![];
+        macro_rules! step {
+            ($name:expr, $result:expr) => {{
+                let result = $result;
+                let failed = result.is_err();
+                steps.push(($name, result));
+                if failed {
+                    return steps;
+                }
+            }};
+        }
+        if let Some(bitstream) = &self.bitstream {
+            step!(
+                "bitstream".to_owned(),
+                apply_bitstream(bitstream, state).await
+            );
+        }
+        if let Some(clock) = &self.clock {
+            step!("clock".to_owned(), apply_clock(clock, state).await);
+        }
+        if let Some(adc) = &self.adc {
+            step!("adc".to_owned(), apply_adc(adc, state).await);
+        }
+        for gbe in &self.gbe {
+            step!(format!("gbe {}", gbe.core), apply_gbe(gbe, state).await);
+        }
+        for (register, &value) in &self.registers {
+            step!(
+                format!("register {}", register),
+                apply_register(register, value, state).await
+            );
+        }
+        if let Some(eq) = &self.eq {
+            step!("eq".to_owned(), apply_eq(eq, state).await);
+        }
+        steps
+    }
+}
+
+/// The names of the registers an `.fpg` file's header describes, which the server lists once it's loaded
+fn design_registers(fpg: &[u8]) -> Vec<String> {
+    // The header is katcp requests, up to a ?quit before the bitstream
+    fpg.split(|&b| b == b'\n')
+        .map(String::from_utf8_lossy)
+        .take_while(|line| !line.starts_with("?quit"))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("?register"), Some(name)) => Some(name.to_owned()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+async fn apply_bitstream(bitstream: &BitstreamConfig, state: &mut State) -> StepResult {
+    // The server can't tell us which file it was programmed with, so the best we can do
+    // is check the loaded design has exactly the registers of the one we want
+    if !bitstream.force && fpga_programmed(state).await? {
+        let fpg = fs::read(&bitstream.path)
+            .map_err(|e| format!("{}: {}", bitstream.path.display(), e))?;
+        let mut wanted = design_registers(&fpg);
+        let mut loaded = list_registers(state).await;
+        wanted.sort();
+        loaded.sort();
+        if !wanted.is_empty() && wanted == loaded {
+            return Ok(false);
+        }
+    }
+    program(&bitstream.path, bitstream.port, state).await?;
+    Ok(true)
+}
+
+async fn apply_register(register: &str, value: u32, state: &mut State) -> StepResult {
+    if !has_register(register, state).await {
+        return Err(format!("The design has no register called {}", register));
+    }
+    let changed = read_int(register, state).await != value;
+    if changed {
+        write_int(register, value, state).await;
+    }
+    Ok(changed)
+}
+
+async fn apply_clock(clock: &ClockConfig, state: &mut State) -> StepResult {
+    let mut changed = false;
+    if let Some(source) = clock.source {
+        if read_bool(clock::SOURCE_REGISTER, state).await != bool::from(source) {
+            write_bool(clock::SOURCE_REGISTER, source.into(), state).await;
+            changed = true;
+        }
+    }
+    if let Some(freq) = clock.frequency {
+        let settings = PllSettings::new(freq, clock.reference)?;
+        if lmx::read_settings(state).await != settings || !lmx::locked(state).await {
+            lmx::program(&settings, clock.reference, clock.power, state).await;
+            if !lmx::wait_for_lock(Duration::from_secs(1), state).await {
+                return Err("The synthesizer did not lock, is the reference present?".to_owned());
+            }
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+async fn apply_adc(adc: &AdcConfig, state: &mut State) -> StepResult {
+    let mode = adc.mode()?;
+    // The ADCs are write-only, so unless we've set them already we can't tell
+    if (0..NUM_CHIPS).all(|chip| state.adc.channel_mode(chip) == Ok(mode)) {
+        return Ok(false);
+    }
+    set_adc_channel_mode(ALL_CHIPS, mode, state).await;
+    Ok(true)
+}
+
+async fn apply_gbe(gbe: &GbeConfig, state: &mut State) -> StepResult {
+    if !has_register(&gbe.core, state).await {
+        return Err(format!("The design has no 10GbE core called {}", gbe.core));
+    }
+    let (mut current, enabled) = read_gbe_settings(&gbe.core, state).await;
+    if gbe.settings.mac.is_none() {
+        current.mac = None;
+    }
+    if enabled && current == gbe.settings {
+        return Ok(false);
+    }
+    config_gbe(&gbe.core, &gbe.settings, state).await;
+    Ok(true)
+}
+
+async fn apply_eq(eq: &EqConfig, state: &mut State) -> StepResult {
+    let format = eq.format();
+    let coeffs = eq.coefficients()?;
+    let current = read_coefficients(&eq.bram, eq.channels, &format, state).await;
+    // Compare what would actually end up in the BRAM
+    if format.quantize(&current).0 == format.quantize(&coeffs).0 {
+        return Ok(false);
+    }
+    write_coefficients(&eq.bram, &coeffs, &format, state).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_board_config() {
+        let config = BoardConfig::parse(
+            r#"
+            bitstream = { path = "grex.fpg" }
+            clock = { source = "external", frequency = 500 }
+            registers = { acc_len = 8192, fft_shift = 4095 }
+            eq = { coefficients = "eq.npy", channels = 4 }
+
+            [[gbe]]
+            core = "gbe0"
+            mac = "02:00:00:00:00:01"
+            ip = "192.168.5.20"
+            gateway = "192.168.5.1"
+            port = 6000
+            dest_ip = "192.168.5.1"
+            dest_port = 6001
+            "#,
+            Path::new("/etc/snap"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.bitstream.unwrap().path,
+            Path::new("/etc/snap/grex.fpg")
+        );
+        let clock = config.clock.unwrap();
+        assert_eq!(clock.source, Some(ClockSource::External));
+        assert_eq!(clock.reference, lmx::DEFAULT_REFERENCE);
+        assert_eq!(config.adc, None);
+        assert_eq!(config.registers.len(), 2);
+        let gbe = &config.gbe[0];
+        assert_eq!(gbe.settings.netmask, Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(gbe.settings.dest_port, 6001);
+        assert_eq!(
+            config.eq.unwrap().coefficients,
+            CoefficientSource::File("/etc/snap/eq.npy".into())
+        );
+        let eq: EqConfig = toml::from_str("coefficients = [1, 0.5]\nchannels = 2").unwrap();
+        assert_eq!(eq.coefficients().unwrap(), vec![1.0, 0.5]);
+        assert!(BoardConfig::parse("adc = { channels = 3 }", Path::new(".")).is_err());
+        assert!(BoardConfig::parse("clocks = {}", Path::new(".")).is_err());
+        let fpg = b"#!/bin/kcpfpg\n?uploadbin\n?register sys_board_id 0x0 0x4\n\
+                    ?register gbe0 0x10000 0x4000\n?meta sys0 xps:xsg clk_rate 250\n?quit\n\
+                    ?register not_header 0x0 0x4\n";
+        assert_eq!(design_registers(fpg), vec!["sys_board_id", "gbe0"]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Planning mode (`--dry-run`), where we print every request instead of sending it to the board
+//!
+//! Every request gets a successful reply straight back and every read comes back as zeros, as if
+//! the board had just been programmed, so commands run through to the end without touching it.
+use katcp::prelude::*;
+use katcp_casper::Base64Bytes;
+
+/// The request as a line of the plan, with reads and writes decoded
+pub fn describe(request: &Message) -> String {
+    let args = request.arguments();
+    match (request.name().as_str(), args.as_slice()) {
+        ("write", [name, offset, bytes]) => {
+            let bytes = Base64Bytes::from_argument(bytes).map_or_else(|_| vec![], |b| b.0);
+            let hex: Vec<_> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let mut line = format!("write {} @ {}: {}", name, offset_hex(offset), hex.join(" "));
+            // Most of what we write is a single register
+            if let Ok(word) = <[u8; 4]>::try_from(bytes.as_slice()) {
+                line += &format!(" ({})", u32::from_be_bytes(word));
+            }
+            line
+        }
+        ("read", [name, offset, num_bytes]) => {
+            format!(
+                "read {} @ {}: {} bytes",
+                name,
+                offset_hex(offset),
+                num_bytes
+            )
+        }
+        _ => request.to_string().trim_end().to_owned(),
+    }
+}
+
+fn offset_hex(offset: &str) -> String {
+    offset
+        .parse::<u32>()
+        .map_or_else(|_| offset.to_owned(), |o| format!("{:#x}", o))
+}
+
+/// A successful reply to `request`, with whatever arguments the reply needs to deserialize
+pub fn reply(request: &Message) -> Message {
+    let args = request.arguments();
+    let reply_args = match request.name().as_str() {
+        "read" => {
+            let num_bytes = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(0);
+            vec![
+                "ok".to_owned(),
+                Base64Bytes(vec![0; num_bytes]).to_argument(),
+            ]
+        }
+        // These reply with the number of informs that came before
+        "help" | "listbof" | "sensor-list" | "sensor-value" | "version-list" => {
+            vec!["ok".to_owned(), "0".to_owned()]
+        }
+        // These echo back what we asked for
+        "log-level" => [vec!["ok".to_owned()], args].concat(),
+        "sensor-sampling" => args,
+        _ => vec!["ok".to_owned()],
+    };
+    Message::new(MessageKind::Reply, request.name(), request.id(), reply_args)
+        .expect("The request's name and arguments are already valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use katcp_casper::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn test_dry_run() {
+        let write = Write::Request {
+            name: "gbe0".to_owned(),
+            offset: 0x14,
+            bytes: Base64Bytes(vec![192, 168, 5, 20]),
+        }
+        .to_message(None)
+        .unwrap();
+        assert_eq!(
+            describe(&write),
+            "write gbe0 @ 0x14: c0 a8 05 14 (3232236820)"
+        );
+        let read = Read::Request {
+            name: "acc_cnt".to_owned(),
+            offset: 0,
+            num_bytes: 4,
+        }
+        .to_message(None)
+        .unwrap();
+        assert_eq!(describe(&read), "read acc_cnt @ 0x0: 4 bytes");
+        let reply: Read = reply(&read).try_into().unwrap();
+        assert_eq!(
+            reply,
+            Read::Reply {
+                ret_code: RetCode::Ok,
+                bytes: Base64Bytes(vec![0; 4])
+            }
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Routines for loading the per-channel equalization (requantization gain) coefficients
+//!
+//! The coefficients live in a shared BRAM, one unsigned fixed point word per channel,
+//! stored big endian like every other CASPER register.
+use std::{fs, path::Path, str::FromStr};
+
+use tracing::{debug, warn};
+
+use crate::{
+    api::{read, write},
+    export::read_npy,
+    utils::State,
+};
+
+/// The number of frequency channels coming out of the FFT
+pub const DEFAULT_CHANNELS: usize = 2048;
+/// The BRAM holding the coefficients
+pub const DEFAULT_BRAM: &str = "eq_coeffs";
+
+/// The unsigned fixed point format of each coefficient
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqFormat {
+    /// The width of the word in bits, a multiple of 8 up to 64
+    pub width: u32,
+    pub bin_pt: u32,
+}
+
+impl Default for EqFormat {
+    fn default() -> Self {
+        Self {
+            width: 32,
+            bin_pt: 16,
+        }
+    }
+}
+
+impl EqFormat {
+    pub fn check(&self) -> Result<(), String> {
+        if self.width == 0 || self.width > 64 || !self.width.is_multiple_of(8) {
+            return Err("The coefficient width must be a multiple of 8 bits, up to 64".to_owned());
+        }
+        if self.bin_pt > self.width {
+            return Err("The binary point must be within the coefficient".to_owned());
+        }
+        Ok(())
+    }
+
+    fn word_bytes(&self) -> usize {
+        self.width as usize / 8
+    }
+
+    fn max_word(&self) -> u64 {
+        u64::MAX >> (64 - self.width)
+    }
+
+    /// Converts `coeffs` into big endian words, returning them and how many saturated
+    pub fn quantize(&self, coeffs: &[f64]) -> (Vec<u8>, usize) {
+        let scale = 2f64.powi(self.bin_pt as i32);
+        let mut saturated = 0;
+        let mut bytes = Vec::with_capacity(coeffs.len() * self.word_bytes());
+        for &coeff in coeffs {
+            let word = (coeff * scale).round();
+            let word = if word < 0.0 || word > self.max_word() as f64 || word.is_nan() {
+                saturated += 1;
+                word.clamp(0.0, self.max_word() as f64) as u64
+            } else {
+                word as u64
+            };
+            bytes.extend_from_slice(&word.to_be_bytes()[8 - self.word_bytes()..]);
+        }
+        (bytes, saturated)
+    }
+
+    /// Converts big endian words back into coefficients
+    pub fn dequantize(&self, bytes: &[u8]) -> Vec<f64> {
+        let scale = 2f64.powi(self.bin_pt as i32);
+        bytes
+            .chunks_exact(self.word_bytes())
+            .map(|word| word.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64) as f64 / scale)
+            .collect()
+    }
+}
+
+/// Where the coefficients come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Coefficients {
+    /// The same gain for every channel
+    Scalar(f64),
+    /// A gain per channel
+    Array(Vec<f64>),
+}
+
+impl Coefficients {
+    /// Loads a gain per channel from a `.npy` file, or a text file of numbers separated by
+    /// whitespace or commas (with `#` comments)
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if path.extension().is_some_and(|ext| ext == "npy") {
+            return read_npy(path).map(Self::Array).map_err(|e| e.to_string());
+        }
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        text.lines()
+            .flat_map(|line| {
+                line.split('#')
+                    .next()
+                    .unwrap_or_default()
+                    .split([',', ' ', '\t'])
+            })
+            .filter(|word| !word.is_empty())
+            .map(|word| {
+                word.parse()
+                    .map_err(|_| format!("{}: Bad coefficient {}", path.display(), word))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self::Array)
+    }
+
+    /// The gain of each of `channels` channels
+    pub fn expand(&self, channels: usize) -> Result<Vec<f64>, String> {
+        match self {
+            Self::Scalar(coeff) => Ok(vec![*coeff; channels]),
+            Self::Array(coeffs) if coeffs.len() == channels => Ok(coeffs.clone()),
+            Self::Array(coeffs) => Err(format!(
+                "Got {} coefficients, but there are {} channels",
+                coeffs.len(),
+                channels
+            )),
+        }
+    }
+}
+
+impl FromStr for Coefficients {
+    type Err = String;
+
+    /// Parses a scalar gain, or loads the gains from the file at the given path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(coeff) => Ok(Self::Scalar(coeff)),
+            Err(_) => Self::load(Path::new(s)),
+        }
+    }
+}
+
+/// Writes the gains for every channel to `bram` and reads them back to make sure they stuck
+pub async fn write_coefficients(
+    bram: &str,
+    coeffs: &[f64],
+    format: &EqFormat,
+    state: &mut State,
+) -> Result<(), String> {
+    format.check()?;
+    let (bytes, saturated) = format.quantize(coeffs);
+    if saturated > 0 {
+        warn!(
+            "{} of {} coefficients saturated, the format holds 0 to {}",
+            saturated,
+            coeffs.len(),
+            format.max_word() as f64 / 2f64.powi(format.bin_pt as i32)
+        );
+    }
+    write(bram, 0, &bytes, state).await;
+    let readback = read(bram, 0, bytes.len() as u32, state).await;
+    if readback != bytes {
+        let bad = bytes
+            .chunks_exact(format.word_bytes())
+            .zip(readback.chunks(format.word_bytes()))
+            .filter(|(wrote, read)| wrote != read)
+            .count();
+        return Err(format!(
+            "{} of {} coefficients didn't read back as written",
+            bad,
+            coeffs.len()
+        ));
+    }
+    debug!(bram, channels = coeffs.len(), "Wrote EQ coefficients");
+    Ok(())
+}
+
+/// Reads back the gains for `channels` channels from `bram`
+pub async fn read_coefficients(
+    bram: &str,
+    channels: usize,
+    format: &EqFormat,
+    state: &mut State,
+) -> Vec<f64> {
+    let bytes = read(bram, 0, (channels * format.word_bytes()) as u32, state).await;
+    format.dequantize(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eq_format() {
+        let format = EqFormat {
+            width: 16,
+            bin_pt: 8,
+        };
+        let (bytes, saturated) = format.quantize(&[1.0, 0.5, -1.0, 300.0]);
+        assert_eq!(bytes, vec![0x01, 0x00, 0x00, 0x80, 0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(saturated, 2);
+        assert_eq!(
+            format.dequantize(&bytes),
+            vec![1.0, 0.5, 0.0, 65535.0 / 256.0]
+        );
+        assert_eq!(
+            Coefficients::Scalar(2.0).expand(4),
+            Ok(vec![2.0, 2.0, 2.0, 2.0])
+        );
+        assert!(Coefficients::Array(vec![1.0]).expand(4).is_err());
+        assert!(EqFormat {
+            width: 12,
+            bin_pt: 0
+        }
+        .check()
+        .is_err());
+    }
+}
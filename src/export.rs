@@ -0,0 +1,263 @@
+//! Routines for writing captured snapshots to disk, so they can be analysed elsewhere (and reading arrays back)
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use clap::ArgEnum;
+use serde::Serialize;
+
+use crate::snapshot::{Capture, Field, FieldType};
+
+/// The file formats we can export a capture to
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One NumPy `.npy` file per field
+    Npy,
+    /// A single CSV file with a column per field
+    Csv,
+    /// The BRAM contents as-is, with a JSON file describing them
+    Raw,
+}
+
+/// Everything needed to make sense of a raw capture, written next to it as JSON
+#[derive(Debug, Serialize)]
+struct Sidecar<'a> {
+    snapshot: &'a str,
+    /// Fields from most to least significant bits of each word
+    fields: &'a [Field],
+    word_bytes: usize,
+    byte_order: &'static str,
+    samples: usize,
+    /// In Hz
+    sample_rate: Option<f64>,
+    capture_time: DateTime<Utc>,
+}
+
+/// Appends `suffix` to the file name of `prefix`
+fn with_suffix(prefix: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = prefix.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// The NumPy dtype that holds every value of `field` without loss
+fn dtype(field: &Field) -> &'static str {
+    match field.ty {
+        FieldType::Bool => "|b1",
+        _ if field.bin_pt > 0 || field.width > 64 => "<f8",
+        FieldType::Unsigned => match field.width {
+            0..=8 => "|u1",
+            9..=16 => "<u2",
+            17..=32 => "<u4",
+            _ => "<u8",
+        },
+        FieldType::Signed => match field.width {
+            0..=8 => "|i1",
+            9..=16 => "<i2",
+            17..=32 => "<i4",
+            _ => "<i8",
+        },
+    }
+}
+
+/// Serializes `value` as `dtype`, which must be one of the dtypes from [`dtype`]
+fn dtype_bytes(dtype: &str, value: f64) -> Vec<u8> {
+    match dtype {
+        "|b1" | "|u1" => vec![value as u8],
+        "|i1" => (value as i8).to_le_bytes().to_vec(),
+        "<u2" => (value as u16).to_le_bytes().to_vec(),
+        "<i2" => (value as i16).to_le_bytes().to_vec(),
+        "<u4" => (value as u32).to_le_bytes().to_vec(),
+        "<i4" => (value as i32).to_le_bytes().to_vec(),
+        "<u8" => (value as u64).to_le_bytes().to_vec(),
+        "<i8" => (value as i64).to_le_bytes().to_vec(),
+        _ => value.to_le_bytes().to_vec(),
+    }
+}
+
+/// Builds the header of a version 1.0 `.npy` file holding a 1D array of `len` elements
+fn npy_header(dtype: &str, len: usize) -> Vec<u8> {
+    let dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+        dtype, len
+    );
+    // The magic string, version, header length, and the header (ending in a newline)
+    // need to add up to a multiple of 64 bytes
+    let unpadded = 10 + dict.len() + 1;
+    let padding = (64 - unpadded % 64) % 64;
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&((dict.len() + padding + 1) as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.resize(header.len() + padding, b' ');
+    header.push(b'\n');
+    header
+}
+
+/// Reads a 1D `.npy` file of (little endian) numbers of any type as f64s
+pub fn read_npy(path: &Path) -> io::Result<Vec<f64>> {
+    let bad = |msg: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), msg),
+        )
+    };
+    let bytes = std::fs::read(path)?;
+    if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+        return Err(bad("Not a .npy file"));
+    }
+    // Version 1 has a two byte header length, later versions four
+    let (header_start, header_len) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        _ if bytes.len() >= 12 => (
+            12,
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+        ),
+        _ => return Err(bad("Truncated header")),
+    };
+    let data_start = header_start + header_len;
+    let header = bytes
+        .get(header_start..data_start)
+        .map(String::from_utf8_lossy)
+        .ok_or_else(|| bad("Truncated header"))?;
+    if header.contains("'fortran_order': True") {
+        return Err(bad("Fortran ordered arrays aren't supported"));
+    }
+    let descr = header
+        .split("'descr':")
+        .nth(1)
+        .and_then(|rest| rest.split('\'').nth(1))
+        .ok_or_else(|| bad("No dtype in header"))?;
+    let size: usize = descr
+        .get(2..)
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| bad("Bad dtype"))?;
+    if descr.starts_with('>') && size > 1 {
+        return Err(bad("Big endian arrays aren't supported"));
+    }
+    let convert: fn(&[u8]) -> f64 = match (&descr[1..2], size) {
+        ("f", 8) => |b| f64::from_le_bytes(b.try_into().unwrap()),
+        ("f", 4) => |b| f32::from_le_bytes(b.try_into().unwrap()) as f64,
+        ("i", 1) => |b| b[0] as i8 as f64,
+        ("i", 2) => |b| i16::from_le_bytes(b.try_into().unwrap()) as f64,
+        ("i", 4) => |b| i32::from_le_bytes(b.try_into().unwrap()) as f64,
+        ("i", 8) => |b| i64::from_le_bytes(b.try_into().unwrap()) as f64,
+        ("u" | "b", 1) => |b| b[0] as f64,
+        ("u", 2) => |b| u16::from_le_bytes(b.try_into().unwrap()) as f64,
+        ("u", 4) => |b| u32::from_le_bytes(b.try_into().unwrap()) as f64,
+        ("u", 8) => |b| u64::from_le_bytes(b.try_into().unwrap()) as f64,
+        _ => return Err(bad("Unsupported dtype")),
+    };
+    Ok(bytes[data_start..]
+        .chunks_exact(size)
+        .map(convert)
+        .collect())
+}
+
+fn write_npy(path: &Path, field: &Field, column: &[f64]) -> io::Result<()> {
+    let dtype = dtype(field);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&npy_header(dtype, column.len()))?;
+    for &value in column {
+        file.write_all(&dtype_bytes(dtype, value))?;
+    }
+    file.flush()
+}
+
+fn write_csv(path: &Path, capture: &Capture) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let names: Vec<_> = capture.fields.iter().map(|f| f.name.as_str()).collect();
+    writeln!(file, "{}", names.join(","))?;
+    for i in 0..capture.len() {
+        let row: Vec<_> = capture.columns.iter().map(|c| c[i].to_string()).collect();
+        writeln!(file, "{}", row.join(","))?;
+    }
+    file.flush()
+}
+
+fn write_raw(
+    path: &Path,
+    sidecar_path: &Path,
+    capture: &Capture,
+    name: &str,
+    sample_rate: Option<f64>,
+) -> io::Result<()> {
+    std::fs::write(path, &capture.raw)?;
+    let sidecar = Sidecar {
+        snapshot: name,
+        fields: &capture.fields,
+        word_bytes: capture.word_bytes(),
+        byte_order: "big",
+        samples: capture.len(),
+        sample_rate,
+        capture_time: capture.timestamp,
+    };
+    let json = serde_json::to_string_pretty(&sidecar).expect("The sidecar always serializes");
+    std::fs::write(sidecar_path, json)
+}
+
+/// Writes `capture` (from the snapshot `name`) as `format` to files starting with `prefix`, returning the files written
+pub fn export(
+    capture: &Capture,
+    name: &str,
+    prefix: &Path,
+    format: Format,
+    sample_rate: Option<f64>,
+) -> io::Result<Vec<PathBuf>> {
+    Ok(match format {
+        Format::Npy => {
+            let mut paths = vec![];
+            for (field, column) in capture.fields.iter().zip(&capture.columns) {
+                let path = with_suffix(prefix, &format!("_{}.npy", field.name));
+                write_npy(&path, field, column)?;
+                paths.push(path);
+            }
+            paths
+        }
+        Format::Csv => {
+            let path = with_suffix(prefix, ".csv");
+            write_csv(&path, capture)?;
+            vec![path]
+        }
+        Format::Raw => {
+            let path = with_suffix(prefix, ".bin");
+            let sidecar_path = with_suffix(prefix, ".json");
+            write_raw(&path, &sidecar_path, capture, name, sample_rate)?;
+            vec![path, sidecar_path]
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_npy_header() {
+        let header = npy_header("<i2", 1024);
+        assert_eq!(header.len() % 64, 0);
+        assert_eq!(&header[..6], b"\x93NUMPY");
+        assert_eq!(
+            u16::from_le_bytes([header[8], header[9]]) as usize,
+            header.len() - 10
+        );
+        assert_eq!(*header.last().unwrap(), b'\n');
+        let re: Field = "re:12:0:signed".parse().unwrap();
+        assert_eq!(dtype(&re), "<i2");
+        let power: Field = "power:32:16:unsigned".parse().unwrap();
+        assert_eq!(dtype(&power), "<f8");
+    }
+
+    #[test]
+    fn test_npy_roundtrip() {
+        let path = std::env::temp_dir().join("snapctl-test-roundtrip.npy");
+        let re: Field = "re:12:0:signed".parse().unwrap();
+        let column = vec![-2048.0, 0.0, 5.0, 2047.0];
+        write_npy(&path, &re, &column).unwrap();
+        assert_eq!(read_npy(&path).unwrap(), column);
+        std::fs::remove_file(path).unwrap();
+    }
+}
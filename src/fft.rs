@@ -0,0 +1,208 @@
+//! Routines for configuring the FFT's shift schedule and watching it for overflows
+//!
+//! Each bit of the shift register halves the data after one butterfly stage, with the most
+//! significant bit controlling the first stage. Shifting too little overflows the FFT, shifting
+//! too much throws away dynamic range.
+use std::{fmt, str::FromStr};
+
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info};
+
+use crate::{
+    api::{read_int, write_int},
+    utils::State,
+};
+
+/// The register holding the shift schedule
+pub const SHIFT_REGISTER: &str = "fft_shift";
+/// The register counting the spectra in which the FFT overflowed
+pub const OVERFLOW_REGISTER: &str = "fft_of_cnt";
+/// The register that clears the overflow counter on its rising edge
+pub const OVERFLOW_RESET_REGISTER: &str = "fft_of_rst";
+/// The number of butterfly stages in a 4096 point FFT
+pub const DEFAULT_STAGES: u32 = 12;
+
+/// The mask with a bit for each of `stages` stages
+fn stage_mask(stages: u32) -> u32 {
+    u32::MAX
+        .checked_shr(32u32.saturating_sub(stages))
+        .unwrap_or(0)
+}
+
+/// Which of the FFT's stages shift their output down by one bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShiftSchedule {
+    pub mask: u32,
+    pub stages: u32,
+}
+
+impl ShiftSchedule {
+    /// Shift after every one of `stages` stages
+    pub fn all(stages: u32) -> Self {
+        Self {
+            mask: stage_mask(stages),
+            stages,
+        }
+    }
+
+    /// Builds a schedule from whether each stage shifts, first stage first
+    pub fn from_stages(stages: &[bool]) -> Self {
+        Self {
+            mask: stages.iter().fold(0, |acc, &s| (acc << 1) | s as u32),
+            stages: stages.len() as u32,
+        }
+    }
+
+    /// The same mask for an FFT of `stages` stages, as long as it has no bits for stages beyond them
+    pub fn with_stages(self, stages: u32) -> Result<Self, String> {
+        if self.mask & !stage_mask(stages) != 0 {
+            return Err(format!(
+                "The shift schedule {} has more than the {} stages of the FFT",
+                self, stages
+            ));
+        }
+        Ok(Self {
+            mask: self.mask,
+            stages,
+        })
+    }
+
+    /// Whether each stage shifts, first stage first
+    pub fn to_stages(self) -> Vec<bool> {
+        (0..self.stages)
+            .rev()
+            .map(|bit| self.mask & (1 << bit) != 0)
+            .collect()
+    }
+}
+
+impl fmt::Display for ShiftSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#0width$b}",
+            self.mask,
+            width = self.stages as usize + 2
+        )
+    }
+}
+
+impl FromStr for ShiftSchedule {
+    type Err = String;
+
+    /// Parses a schedule from a mask (`0b1111_1111_1111`, `0xfff`, or `4095`) or a per-stage list (`1,1,0,...`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.replace('_', "");
+        if s.contains(',') {
+            let stages = s
+                .split(',')
+                .map(|stage| match stage.trim() {
+                    "1" => Ok(true),
+                    "0" => Ok(false),
+                    _ => Err("Each stage must be 0 or 1".to_owned()),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if stages.len() > 32 {
+                return Err("There can be at most 32 stages".to_owned());
+            }
+            return Ok(Self::from_stages(&stages));
+        }
+        let (digits, radix) = if let Some(bin) = s.strip_prefix("0b") {
+            (bin, 2)
+        } else if let Some(hex) = s.strip_prefix("0x") {
+            (hex, 16)
+        } else {
+            (s.as_str(), 10)
+        };
+        let mask = u32::from_str_radix(digits, radix).map_err(|_| "Bad shift mask")?;
+        let stages = if radix == 2 {
+            digits.len() as u32
+        } else {
+            DEFAULT_STAGES.max(32 - mask.leading_zeros())
+        };
+        if stages > 32 {
+            return Err("There can be at most 32 stages".to_owned());
+        }
+        Ok(Self { mask, stages })
+    }
+}
+
+pub async fn set_shift(schedule: ShiftSchedule, state: &mut State) {
+    write_int(SHIFT_REGISTER, schedule.mask, state).await;
+}
+
+pub async fn get_shift(stages: u32, state: &mut State) -> ShiftSchedule {
+    ShiftSchedule {
+        mask: read_int(SHIFT_REGISTER, state).await & stage_mask(stages),
+        stages,
+    }
+}
+
+/// Reads the number of spectra that have overflowed since the counter was last cleared
+pub async fn overflows(state: &mut State) -> u32 {
+    read_int(OVERFLOW_REGISTER, state).await
+}
+
+pub async fn clear_overflows(state: &mut State) {
+    write_int(OVERFLOW_RESET_REGISTER, 1, state).await;
+    write_int(OVERFLOW_RESET_REGISTER, 0, state).await;
+}
+
+/// Finds the schedule with the fewest shifts that doesn't overflow over `duration` of data
+///
+/// Starting from shifting every stage, this greedily stops shifting stages (first stage first)
+/// as long as the FFT doesn't overflow. The FFT is left with the schedule we found.
+pub async fn auto_shift(stages: u32, duration: Duration, state: &mut State) -> ShiftSchedule {
+    let mut schedule = ShiftSchedule::all(stages);
+    for bit in (0..stages).rev() {
+        let candidate = ShiftSchedule {
+            mask: schedule.mask & !(1 << bit),
+            stages,
+        };
+        set_shift(candidate, state).await;
+        clear_overflows(state).await;
+        sleep(duration).await;
+        let count = overflows(state).await;
+        debug!(%candidate, count, "Tried shift schedule");
+        if count == 0 {
+            schedule = candidate;
+        }
+    }
+    set_shift(schedule, state).await;
+    clear_overflows(state).await;
+    info!(%schedule, "Found the smallest shift schedule without overflows");
+    schedule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_schedule() {
+        let schedule: ShiftSchedule = "0b1111_1010_1010".parse().unwrap();
+        assert_eq!(schedule.mask, 0xfaa);
+        assert_eq!(schedule.stages, 12);
+        assert_eq!(schedule.to_string(), "0b111110101010");
+        let stages: ShiftSchedule = "1,1,1,1,1,0,1,0,1,0,1,0".parse().unwrap();
+        assert_eq!(stages, schedule);
+        assert_eq!(ShiftSchedule::from_stages(&schedule.to_stages()), schedule);
+        assert_eq!("0xfff".parse(), Ok(ShiftSchedule::all(12)));
+        assert!("1,2,1".parse::<ShiftSchedule>().is_err());
+        assert_eq!(
+            "0xff"
+                .parse::<ShiftSchedule>()
+                .unwrap()
+                .with_stages(8)
+                .unwrap()
+                .to_string(),
+            "0b11111111"
+        );
+        assert!("0xfff"
+            .parse::<ShiftSchedule>()
+            .unwrap()
+            .with_stages(8)
+            .is_err());
+        assert_eq!(stage_mask(32), u32::MAX);
+    }
+}
@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 
-use katcp::{messages::log::*, prelude::*};
+use katcp::{
+    messages::{log::*, sensors::Status},
+    prelude::*,
+};
 use katcp_casper::*;
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     net::tcp::OwnedReadHalf,
-    sync::mpsc::UnboundedSender,
+    sync::{mpsc::UnboundedSender, watch},
 };
 use tracing::{debug, error, info, trace, warn};
 
@@ -33,24 +36,56 @@ fn handle_log(log_msg: Message) {
     };
 }
 
-fn handle_fpga(fpga_msg: Message) {
+fn handle_fpga(fpga_msg: Message, fpga_status: &watch::Sender<Option<FpgaStatus>>) {
     match fpga_msg.try_into() {
-        Ok(Fpga::Inform { status }) => match status {
-            FpgaStatus::Loaded => info!("FPGA Loaded"),
-            FpgaStatus::Ready => info!("FPGA Ready"),
-            FpgaStatus::Down => info!("FPGA Down"),
-            FpgaStatus::Mapped => info!("FPGA Mapped"),
-        },
+        Ok(Fpga::Inform { status }) => {
+            match status {
+                FpgaStatus::Loaded => info!("FPGA Loaded"),
+                FpgaStatus::Ready => info!("FPGA Ready"),
+                FpgaStatus::Down => info!("FPGA Down"),
+                FpgaStatus::Mapped => info!("FPGA Mapped"),
+            }
+            // Nobody might be watching, which is fine
+            let _ = fpga_status.send(Some(status));
+        }
         Err(e) => {
             error!(?e, "Couldn't deserialize `fpga`")
         }
     };
 }
 
-pub(crate) fn make_inform_dispatchers() -> Dispatchers {
+fn handle_sensor_status(status_msg: Message) {
+    match status_msg.try_into() {
+        Ok(SensorStatus::Inform(updates)) => {
+            for SensorReading {
+                name,
+                status,
+                value,
+            } in updates.readings
+            {
+                match status {
+                    Status::Warn | Status::Error | Status::Failure => {
+                        warn!(%name, status = %status.to_argument(), %value, "Sensor")
+                    }
+                    _ => info!(%name, status = %status.to_argument(), %value, "Sensor"),
+                }
+            }
+        }
+        Err(e) => error!(?e, "Couldn't deserialize `sensor-status`"),
+    };
+}
+
+/// Makes the dispatchers for the informs we handle, publishing `#fpga` statuses to `fpga_status`
+pub(crate) fn make_inform_dispatchers(
+    fpga_status: watch::Sender<Option<FpgaStatus>>,
+) -> Dispatchers {
     let mut dispatchers: Dispatchers = HashMap::new();
     dispatchers.insert("log".to_owned(), Box::new(handle_log));
-    dispatchers.insert("fpga".to_owned(), Box::new(handle_fpga));
+    dispatchers.insert(
+        "fpga".to_owned(),
+        Box::new(move |msg| handle_fpga(msg, &fpga_status)),
+    );
+    dispatchers.insert("sensor-status".to_owned(), Box::new(handle_sensor_status));
     dispatchers
 }
 
@@ -64,10 +99,14 @@ pub(crate) async fn handle_informs(
     let mut lines = BufReader::new(reader).lines();
     loop {
         // Grab message (or an empty line)
-        let incoming_line = lines
-            .next_line()
-            .await
-            .expect("Error awaiting for an incoming line. This was probably a socket error?");
+        let incoming_line = match lines.next_line().await {
+            Ok(line) => line,
+            // A server going down (like on ?restart) can reset the connection
+            Err(e) => {
+                warn!(?e, "Socket was closed with an error");
+                break;
+            }
+        };
         if let Some(line) = incoming_line {
             if line.is_empty() {
                 continue;
@@ -0,0 +1,453 @@
+//! Routines for programming the LMX2581 frequency synthesizer that generates the ADC sample clock
+use packed_struct::prelude::*;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::{
+    api::{read_int, write_int},
+    register_address,
+    utils::{RegisterAddress, State},
+};
+
+// The synthesizer's SPI bus is exposed through a single wishbone register.
+// Writing a word to it shifts the word out to the chip (MSB first), and reading it
+// returns the bits the chip shifted back during the last transfer.
+// Every LMX2581 register is 32 bits, with the register address in the bottom nibble.
+
+/// The name of the wishbone register that drives the synthesizer's SPI bus
+pub const CONTROLLER: &str = "lmx_ctrl";
+/// The reference oscillator on the SNAP, in MHz
+pub const DEFAULT_REFERENCE: f64 = 10.0;
+
+/// The range of reference frequencies the synthesizer accepts (OSCin), in MHz
+pub const REFERENCE_MIN: f64 = 5.0;
+pub const REFERENCE_MAX: f64 = 900.0;
+/// The fastest the phase detector runs, in MHz, which the R divider brings faster references down to
+pub const PFD_MAX: f64 = 200.0;
+/// The range of the internal VCO, in MHz
+pub const VCO_MIN: f64 = 1880.0;
+pub const VCO_MAX: f64 = 3760.0;
+/// The largest fractional denominator (22 bits)
+pub const MAX_DEN: u32 = (1 << 22) - 1;
+/// The smallest integer part of the N divider in fractional mode
+pub const MIN_N: u32 = 16;
+/// The largest integer part of the N divider (12 bits)
+pub const MAX_N: u32 = (1 << 12) - 1;
+
+/// The bit of the readback diagnostics (RD_DIAGNOSTICS) that is set when the PLL is locked (DLD)
+const LOCK_DETECT: u32 = 1 << 2;
+
+/// Registers that are reserved, but must be programmed with these values as per the datasheet
+const RESERVED_REGISTERS: [u32; 6] = [
+    0x021F_E80F, // R15
+    0x4082_C10D, // R13
+    0x2100_50CA, // R10
+    0x03C7_C039, // R9
+    0x207D_DBF8, // R8
+    0x0008_2317, // R7
+];
+
+#[derive(Debug, Copy, Clone)]
+#[repr(u8)]
+enum LmxAddress {
+    R0 = 0,
+    R1 = 1,
+    R2 = 2,
+    R3 = 3,
+    R4 = 4,
+    R5 = 5,
+    R6 = 6,
+}
+
+register_address! {LmxAddress,R0}
+register_address! {LmxAddress,R1}
+register_address! {LmxAddress,R2}
+register_address! {LmxAddress,R3}
+register_address! {LmxAddress,R4}
+register_address! {LmxAddress,R5}
+register_address! {LmxAddress,R6}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R0 {
+    #[packed_field(bits = "31")]
+    pub id: bool,
+    #[packed_field(bits = "29..=30")]
+    pub frac_dither: Integer<u8, packed_bits::Bits<2>>,
+    #[packed_field(bits = "28")]
+    pub no_fcal: bool,
+    #[packed_field(bits = "16..=27", endian = "msb")]
+    pub pll_n: Integer<u16, packed_bits::Bits<12>>,
+    /// The low 12 bits of the fractional numerator
+    #[packed_field(bits = "4..=15", endian = "msb")]
+    pub pll_num_lsb: Integer<u16, packed_bits::Bits<12>>,
+}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R1 {
+    #[packed_field(bits = "27..=31")]
+    pub cpg: Integer<u8, packed_bits::Bits<5>>,
+    #[packed_field(bits = "25..=26")]
+    pub vco_sel: Integer<u8, packed_bits::Bits<2>>,
+    /// The high 10 bits of the fractional numerator
+    #[packed_field(bits = "15..=24", endian = "msb")]
+    pub pll_num_msb: Integer<u16, packed_bits::Bits<10>>,
+    #[packed_field(bits = "12..=14")]
+    pub frac_order: Integer<u8, packed_bits::Bits<3>>,
+    #[packed_field(bits = "4..=11")]
+    pub pll_r: u8,
+}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R2 {
+    #[packed_field(bits = "29")]
+    pub osc_2x: bool,
+    #[packed_field(bits = "27")]
+    pub cpp: bool,
+    #[packed_field(bits = "4..=25", endian = "msb")]
+    pub pll_den: Integer<u32, packed_bits::Bits<22>>,
+}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R3 {
+    /// The output divider is 2 * (vco_div + 1)
+    #[packed_field(bits = "18..=22")]
+    pub vco_div: Integer<u8, packed_bits::Bits<5>>,
+    #[packed_field(bits = "12..=17")]
+    pub outb_pwr: Integer<u8, packed_bits::Bits<6>>,
+    #[packed_field(bits = "6..=11")]
+    pub outa_pwr: Integer<u8, packed_bits::Bits<6>>,
+    #[packed_field(bits = "5")]
+    pub outb_pd: bool,
+    #[packed_field(bits = "4")]
+    pub outa_pd: bool,
+}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R4 {
+    #[packed_field(bits = "29..=31")]
+    pub pfd_dly: Integer<u8, packed_bits::Bits<3>>,
+    #[packed_field(bits = "28")]
+    pub fl_frce: bool,
+    #[packed_field(bits = "16..=27", endian = "msb")]
+    pub fl_toc: Integer<u16, packed_bits::Bits<12>>,
+    #[packed_field(bits = "11..=15")]
+    pub fl_cpg: Integer<u8, packed_bits::Bits<5>>,
+    #[packed_field(bits = "4..=9")]
+    pub cpg_bleed: Integer<u8, packed_bits::Bits<6>>,
+}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R5 {
+    #[packed_field(bits = "24")]
+    pub out_lden: bool,
+    #[packed_field(bits = "21..=23")]
+    pub osc_freq: Integer<u8, packed_bits::Bits<3>>,
+    #[packed_field(bits = "20")]
+    pub bufen_dis: bool,
+    #[packed_field(bits = "15..=16")]
+    pub vco_sel_mode: Integer<u8, packed_bits::Bits<2>>,
+    /// 0 routes the VCO straight to the output, 1 routes it through the divider
+    #[packed_field(bits = "13..=14")]
+    pub outb_mux: Integer<u8, packed_bits::Bits<2>>,
+    #[packed_field(bits = "11..=12")]
+    pub outa_mux: Integer<u8, packed_bits::Bits<2>>,
+    #[packed_field(bits = "10")]
+    pub zero_dly: bool,
+    #[packed_field(bits = "8..=9")]
+    pub mode: Integer<u8, packed_bits::Bits<2>>,
+    #[packed_field(bits = "6..=7")]
+    pub pwdn_mode: Integer<u8, packed_bits::Bits<2>>,
+    #[packed_field(bits = "4")]
+    pub reset: bool,
+}
+
+#[derive(PackedStruct, Debug, Default)]
+#[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
+pub struct R6 {
+    #[packed_field(bits = "11..=31", endian = "msb")]
+    pub rd_diagnostics: Integer<u32, packed_bits::Bits<21>>,
+    /// The register to shift out on the next transfer
+    #[packed_field(bits = "5..=8")]
+    pub rdaddr: Integer<u8, packed_bits::Bits<4>>,
+    #[packed_field(bits = "4")]
+    pub uwire_lock: bool,
+}
+
+/// The divider and PLL settings that produce a given output frequency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PllSettings {
+    pub n: u32,
+    pub num: u32,
+    pub den: u32,
+    pub r: u8,
+    pub osc_2x: bool,
+    /// The output divider, or `None` if the VCO drives the output directly
+    pub vco_div: Option<u8>,
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl PllSettings {
+    /// Computes the settings that produce `freq` from the reference `reference` (both in MHz)
+    pub fn new(freq: f64, reference: f64) -> Result<Self, String> {
+        if !(REFERENCE_MIN..=REFERENCE_MAX).contains(&reference) {
+            return Err(format!(
+                "The reference must be between {} and {} MHz",
+                REFERENCE_MIN, REFERENCE_MAX
+            ));
+        }
+        if !freq.is_finite() || freq <= 0.0 {
+            return Err(format!("{} MHz isn't a frequency we can synthesize", freq));
+        }
+        let vco_div = if (VCO_MIN..=VCO_MAX).contains(&freq) {
+            None
+        } else {
+            Some(
+                (2..=38)
+                    .step_by(2)
+                    .find(|div| (VCO_MIN..=VCO_MAX).contains(&(freq * *div as f64)))
+                    .ok_or_else(|| format!("{} MHz is outside the synthesizer's range", freq))?,
+            )
+        };
+        let vco = freq * vco_div.unwrap_or(1) as f64;
+        // Work in integer Hz so we can find an exact fraction when there is one
+        let vco_hz = (vco * 1e6).round() as u64;
+        let ref_hz = (reference * 1e6).round() as u64;
+        // Divide the reference down until the phase detector is slow enough for N to fit,
+        // so that N = vco / (reference / r) = vco * r / reference
+        let r = (1..=u8::MAX)
+            .find(|&r| {
+                reference / r as f64 <= PFD_MAX && vco_hz * r as u64 / ref_hz >= MIN_N as u64
+            })
+            .ok_or_else(|| format!("No R divider brings a {} MHz reference in range", reference))?;
+        let scaled_hz = vco_hz * r as u64;
+        let n = (scaled_hz / ref_hz) as u32;
+        if !(MIN_N..=MAX_N).contains(&n) {
+            return Err(format!(
+                "{} MHz needs an N divider of {}, which is out of range",
+                freq, n
+            ));
+        }
+        let rem = scaled_hz % ref_hz;
+        let (num, den) = if rem == 0 {
+            (0, 1)
+        } else {
+            let common = gcd(rem, ref_hz);
+            let (num, den) = (rem / common, ref_hz / common);
+            if den > MAX_DEN as u64 {
+                // No exact fraction fits, so get as close as we can
+                (
+                    ((rem as f64 / ref_hz as f64) * MAX_DEN as f64).round() as u64,
+                    MAX_DEN as u64,
+                )
+            } else {
+                (num, den)
+            }
+        };
+        Ok(Self {
+            n,
+            num: num as u32,
+            den: den as u32,
+            r,
+            osc_2x: false,
+            vco_div,
+        })
+    }
+
+    /// The output frequency these settings produce from `reference`, both in MHz
+    pub fn frequency(&self, reference: f64) -> f64 {
+        let pd = reference * if self.osc_2x { 2.0 } else { 1.0 } / self.r as f64;
+        let vco = pd * (self.n as f64 + self.num as f64 / self.den as f64);
+        vco / self.vco_div.unwrap_or(1) as f64
+    }
+}
+
+/// The OSC_FREQ setting for a reference of `reference` MHz
+fn osc_freq(reference: f64) -> u8 {
+    match reference {
+        r if r <= 64.0 => 0,
+        r if r <= 128.0 => 1,
+        r if r <= 256.0 => 2,
+        r if r <= 512.0 => 3,
+        _ => 4,
+    }
+}
+
+fn to_word<T>(packed: &T) -> u32
+where
+    T: PackedStruct<ByteArray = [u8; 4]> + RegisterAddress,
+{
+    u32::from_be_bytes(
+        packed
+            .pack()
+            .expect("An instance of a packed struct should always pack"),
+    ) | T::address() as u32
+}
+
+async fn write_register<T>(packed: T, state: &mut State)
+where
+    T: PackedStruct<ByteArray = [u8; 4]> + RegisterAddress,
+{
+    write_int(CONTROLLER, to_word(&packed), state).await;
+}
+
+/// Reads back register `T` by asking the chip to shift it out on the next transfer
+pub async fn read_register<T>(state: &mut State) -> T
+where
+    T: PackedStruct<ByteArray = [u8; 4]> + RegisterAddress,
+{
+    // The first write selects the register, the second clocks it out
+    for _ in 0..2 {
+        write_register(
+            R6 {
+                rdaddr: T::address().into(),
+                ..Default::default()
+            },
+            state,
+        )
+        .await;
+    }
+    let word = read_int(CONTROLLER, state).await & !0xf;
+    T::unpack(&word.to_be_bytes()).expect("Any 32 bits are a valid register")
+}
+
+/// Returns true if the PLL reports that it is locked
+pub async fn locked(state: &mut State) -> bool {
+    let diag: R6 = read_register(state).await;
+    u32::from(diag.rd_diagnostics) & LOCK_DETECT != 0
+}
+
+/// Programs the synthesizer with `settings`, with output A at `power` (0-47)
+pub async fn program(settings: &PllSettings, reference: f64, power: u8, state: &mut State) {
+    let mux = if settings.vco_div.is_some() { 1 } else { 0 };
+    let r5 = |reset| R5 {
+        osc_freq: osc_freq(reference).into(),
+        outb_mux: mux.into(),
+        outa_mux: mux.into(),
+        reset,
+        ..Default::default()
+    };
+    // Reset, then program R15 down to R0 as the datasheet says, as R0 kicks off the VCO calibration
+    write_register(r5(true), state).await;
+    for word in RESERVED_REGISTERS {
+        write_int(CONTROLLER, word, state).await;
+    }
+    write_register(R6::default(), state).await;
+    write_register(r5(false), state).await;
+    // No fast lock or bleed current
+    write_register(R4::default(), state).await;
+    write_register(
+        R3 {
+            vco_div: (settings.vco_div.unwrap_or(2) / 2 - 1).into(),
+            outb_pwr: 0.into(),
+            outa_pwr: power.into(),
+            outb_pd: true,
+            outa_pd: false,
+        },
+        state,
+    )
+    .await;
+    write_register(
+        R2 {
+            osc_2x: settings.osc_2x,
+            cpp: true,
+            pll_den: settings.den.into(),
+        },
+        state,
+    )
+    .await;
+    write_register(
+        R1 {
+            cpg: 0b11111.into(),
+            pll_num_msb: ((settings.num >> 12) as u16).into(),
+            // Third order modulator, unless we're running integer-N
+            frac_order: (if settings.num == 0 { 0 } else { 3 }).into(),
+            pll_r: settings.r,
+            ..Default::default()
+        },
+        state,
+    )
+    .await;
+    write_register(
+        R0 {
+            pll_n: (settings.n as u16).into(),
+            pll_num_lsb: ((settings.num & 0xfff) as u16).into(),
+            ..Default::default()
+        },
+        state,
+    )
+    .await;
+}
+
+/// Polls the lock detect until the PLL locks, returning false if it didn't within `timeout`
+pub async fn wait_for_lock(timeout: Duration, state: &mut State) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if locked(state).await {
+            return true;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+/// Reads back the settings the synthesizer is currently programmed with
+pub async fn read_settings(state: &mut State) -> PllSettings {
+    let r0: R0 = read_register(state).await;
+    let r1: R1 = read_register(state).await;
+    let r2: R2 = read_register(state).await;
+    let r3: R3 = read_register(state).await;
+    let r5: R5 = read_register(state).await;
+    PllSettings {
+        n: u16::from(r0.pll_n) as u32,
+        num: (u16::from(r1.pll_num_msb) as u32) << 12 | u16::from(r0.pll_num_lsb) as u32,
+        den: u32::from(r2.pll_den).max(1),
+        r: r1.pll_r.max(1),
+        osc_2x: r2.osc_2x,
+        vco_div: if u8::from(r5.outa_mux) == 0 {
+            None
+        } else {
+            Some(2 * (u8::from(r3.vco_div) + 1))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pll_settings() {
+        let settings = PllSettings::new(500.0, 10.0).unwrap();
+        assert_eq!(settings.vco_div, Some(4));
+        assert_eq!((settings.n, settings.num, settings.den), (200, 0, 1));
+        let settings = PllSettings::new(2500.0, 10.0).unwrap();
+        assert_eq!(settings.vco_div, None);
+        let settings = PllSettings::new(333.333, 10.0).unwrap();
+        assert_eq!((settings.n, settings.num, settings.den), (199, 4999, 5000));
+        assert!((settings.frequency(10.0) - 333.333).abs() < 1e-6);
+        assert!(PllSettings::new(10.0, 10.0).is_err());
+        assert!(PllSettings::new(500.0, 0.0).is_err());
+        assert!(PllSettings::new(500.0, f64::NAN).is_err());
+        assert!(PllSettings::new(f64::INFINITY, 10.0).is_err());
+        // A fast reference is divided down to keep the phase detector (and N) in range
+        let settings = PllSettings::new(500.0, 400.0).unwrap();
+        assert_eq!((settings.r, settings.n), (4, 20));
+        assert!(400.0 / settings.r as f64 <= PFD_MAX);
+        assert!((settings.frequency(400.0) - 500.0).abs() < 1e-6);
+        let settings = PllSettings::new(2500.0, 250.0).unwrap();
+        assert!(250.0 / settings.r as f64 <= PFD_MAX);
+        assert!((settings.frequency(250.0) - 2500.0).abs() < 1e-6);
+    }
+}
@@ -1,21 +1,33 @@
 mod adc;
 mod api;
 mod args;
+mod clock;
+mod config;
+mod dry_run;
+mod eq;
+mod export;
+mod fft;
 mod handlers;
+mod lmx;
+mod monitor;
+mod requant;
+mod shell;
+mod snapshot;
+mod spectrometer;
+mod sync;
 mod tengbe;
 mod utils;
+mod xadc;
 
-use std::{error::Error, net::SocketAddr};
+use std::error::Error;
 
 use args::*;
 use clap::Parser;
-use handlers::*;
 use katcp::{messages::log::*, prelude::*};
-use tokio::{net::TcpStream, sync::mpsc::unbounded_channel, task};
 use tracing::{debug, info};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::{api::*, utils::*};
+use crate::{api::*, requant::BitSelect, utils::*};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -25,35 +37,187 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new(if args.verbose { "debug" } else { "info" }))
         .unwrap();
+    // Logs go through the shell's printer, so they can be printed above its prompt
+    let printer = shell::SharedPrinter::default();
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_writer(printer.clone()))
         .with(filter_layer)
         .init();
     debug!("Logging started");
-    // Create the channels
-    let (tx, rx) = unbounded_channel::<Message>();
-    // Connect to the SNAP katcp server
-    let (reader, writer) = TcpStream::connect(SocketAddr::new(args.address, args.port))
-        .await?
-        .into_split();
-    // Startup dispatcher
-    task::spawn(handle_informs(tx, reader, make_inform_dispatchers()));
     // Setup the program state
-    let mut state = State {
-        unhandled_incoming_messages: rx,
-        writer,
-        address: args.address,
+    let mut state = if args.dry_run {
+        info!("Dry run: printing the requests we would send");
+        State::dry(args.address, args.port)
+    } else {
+        // Connect to the SNAP katcp server
+        State::connect(args.address, args.port).await?
     };
     // Do an initial ping to make sure we're actually connected
-    ping(&mut state).await;
-    info!("Connected to the SNAP");
+    state.greeting = ping(&mut state).await;
+    if !state.dry_run() {
+        info!("Connected to the SNAP");
+    }
     // Ask the device  to send us trace level logs, even if we don't use them as we'll filter them here
     set_device_log_level(&mut state, Level::Info).await;
     // Perform the requested action
     // Perform the action
     match args.command {
         Command::Upload { path, port } => upload(path, port, &mut state).await,
-        Command::ConfigGBE { core } => config_gbe(&core, &mut state).await,
+        Command::Deprogram { timeout } => deprogram(timeout, &mut state).await,
+        Command::Images { command } => images(command, &mut state).await,
+        Command::ConfigGBE { core, gbe } => config_gbe(&core, &gbe.settings(), &mut state).await,
+        Command::Apply { config } => apply(&config, &mut state).await,
+        Command::Snapshot { snapshot, export } => {
+            capture_snapshot(
+                &snapshot.snapshot(),
+                &snapshot.options(),
+                snapshot.timeout,
+                &export,
+                &mut state,
+            )
+            .await
+        }
+        Command::Clock { command } => match command {
+            ClockCommand::Set {
+                freq,
+                reference,
+                power,
+            } => clock_set(freq, reference, power, &mut state).await,
+            ClockCommand::Status { reference } => clock_status(reference, &mut state).await,
+            ClockCommand::Source { source, register } => {
+                clock_source(source, &register, &mut state).await
+            }
+            ClockCommand::Measure {
+                expected,
+                tolerance,
+                interval,
+                register,
+            } => clock_measure(&register, interval, expected, tolerance, &mut state).await,
+        },
+        Command::Sync {
+            sample_rate,
+            samples_per_count,
+            output,
+            arm_register,
+            pps_register,
+        } => {
+            sync_pps(
+                &arm_register,
+                &pps_register,
+                sample_rate * 1e6,
+                samples_per_count,
+                &output,
+                &mut state,
+            )
+            .await
+        }
+        Command::Pps {
+            seconds,
+            pps_register,
+            clocks_register,
+        } => pps_health(&pps_register, &clocks_register, seconds, &mut state).await,
+        Command::Fft { command } => fft_command(command, &mut state).await,
+        Command::Eq {
+            coefficients,
+            bram,
+            format,
+        } => {
+            load_eq(
+                coefficients.as_ref(),
+                &bram,
+                format.channels,
+                &format.format(),
+                &mut state,
+            )
+            .await
+        }
+        Command::Spectrum {
+            spectrometer,
+            plot,
+            acc_len,
+            sample_rate,
+            reference,
+            timeout,
+            print,
+        } => {
+            spectrum(
+                &spectrometer.spectrometer(),
+                acc_len,
+                sample_rate,
+                reference,
+                timeout,
+                (!print).then_some(&plot),
+                &mut state,
+            )
+            .await
+        }
+        Command::Requant {
+            snapshot,
+            target,
+            tolerance,
+            iterations,
+            bram,
+            format,
+        } => {
+            let bit_select = BitSelect {
+                snapshot: snapshot.snapshot(),
+                options: snapshot.options(),
+                timeout: snapshot.timeout,
+                bram,
+                channels: format.channels,
+                format: format.format(),
+                target,
+                tolerance,
+                iterations,
+            };
+            select_bits(&bit_select, &mut state).await
+        }
+        Command::Sensors { watch } => sensors(watch, &mut state).await,
+        Command::Xadc { thresholds } => print_xadc(&thresholds.thresholds(), &mut state).await,
+        Command::Monitor {
+            prometheus,
+            interval,
+            cores,
+            registers,
+            pps_register,
+            thresholds,
+        } => {
+            let config = monitor::MonitorConfig {
+                interval,
+                cores,
+                registers,
+                pps_register,
+                thresholds: thresholds.thresholds(),
+            };
+            monitor::run(prometheus, &config, &mut state).await?
+        }
+        Command::Info => info(&mut state).await,
+        Command::Reboot { wait } => shutdown(true, wait, &mut state).await,
+        Command::Halt => shutdown(false, None, &mut state).await,
+        Command::Shell => shell::run(&printer, &mut state).await?,
+        Command::Raw { request } => std::process::exit(raw(&request, &mut state).await),
+        Command::Adc { command } => match command {
+            AdcCommand::Test { pattern1, pattern2 } => {
+                adc_test(pattern1, pattern2, &mut state).await
+            }
+            AdcCommand::Levels { interval, count } => adc_levels(interval, count, &mut state).await,
+            AdcCommand::Mode { channels, chip } => {
+                set_adc_channel_mode(adc::chip_mask(chip), channels, &mut state).await
+            }
+            AdcCommand::Gain {
+                chip,
+                channels,
+                channel,
+                coarse,
+                fine,
+            } => adc_gain(chip, channels, channel, coarse, fine, &mut state).await,
+            AdcCommand::Input {
+                chip,
+                channels,
+                channel,
+                input,
+            } => adc_input(chip, channels, channel, input, &mut state).await,
+        },
     };
     Ok(())
 }
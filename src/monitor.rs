@@ -0,0 +1,317 @@
+//! A long-running monitor that serves the SNAP's health as Prometheus metrics
+//!
+//! We poll the board on an interval and render everything into the Prometheus text format,
+//! which a tiny HTTP server hands out to whoever scrapes us.
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+    time::{sleep, timeout, Duration},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    api::{list_registers, try_read_int, try_read_packed},
+    tengbe::{Counters, Status},
+    utils::State,
+    xadc::{self, Thresholds, XadcChannel},
+};
+
+/// The longest a poll can take before we decide the board has gone away
+const POLL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether a metric only goes up, or can go either way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+/// One sample of a metric
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub help: &'static str,
+    pub ty: MetricType,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl Metric {
+    fn new(name: &str, help: &'static str, ty: MetricType, value: f64) -> Self {
+        Self {
+            name: format!("snap_{}", name),
+            help,
+            ty,
+            labels: vec![],
+            value,
+        }
+    }
+
+    fn label(mut self, key: &'static str, value: &str) -> Self {
+        self.labels.push((key, value.to_owned()));
+        self
+    }
+}
+
+/// Renders `metrics` in the Prometheus text format, with one HELP and TYPE per metric name
+pub fn render(metrics: &[Metric]) -> String {
+    let mut out = String::new();
+    let mut last_name = "";
+    for metric in metrics {
+        if metric.name != last_name {
+            let ty = match metric.ty {
+                MetricType::Counter => "counter",
+                MetricType::Gauge => "gauge",
+            };
+            writeln!(out, "# HELP {} {}", metric.name, metric.help).unwrap();
+            writeln!(out, "# TYPE {} {}", metric.name, ty).unwrap();
+            last_name = &metric.name;
+        }
+        let labels: Vec<_> = metric
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        if labels.is_empty() {
+            writeln!(out, "{} {}", metric.name, metric.value).unwrap();
+        } else {
+            writeln!(
+                out,
+                "{}{{{}}} {}",
+                metric.name,
+                labels.join(","),
+                metric.value
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// What to poll, and how often
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    pub interval: Duration,
+    /// The 10GbE cores to report the link and counters of
+    pub cores: Vec<String>,
+    /// Any other registers to report the value of
+    pub registers: Vec<String>,
+    pub pps_register: String,
+    pub thresholds: Thresholds,
+}
+
+fn up(up: bool) -> Metric {
+    Metric::new(
+        "up",
+        "Whether we could reach the board",
+        MetricType::Gauge,
+        up as u8 as f64,
+    )
+}
+
+/// Reads everything in `config` that exists on the board in `present`
+async fn poll(
+    config: &MonitorConfig,
+    present: &[String],
+    state: &mut State,
+) -> Result<Vec<Metric>, String> {
+    let exists = |name: &str| present.iter().any(|r| r == name);
+    let mut metrics = vec![up(true)];
+    // Keep each metric name together, as the text format expects
+    let mut link = vec![];
+    let mut counters = vec![];
+    for core in config.cores.iter().filter(|c| exists(c)) {
+        let status: Status = try_read_packed(core, state).await?;
+        link.push(
+            Metric::new(
+                "gbe_link_up",
+                "Whether the 10GbE link is up",
+                MetricType::Gauge,
+                status.link_up as u8 as f64,
+            )
+            .label("core", core),
+        );
+        let c: Counters = try_read_packed(core, state).await?;
+        for (name, help, value) in [
+            ("gbe_tx_packets_total", "Packets sent", c.tx_packets),
+            ("gbe_tx_valid_total", "Valid words sent", c.tx_valid),
+            (
+                "gbe_tx_overflow_total",
+                "Transmit buffer overflows",
+                c.tx_overflow,
+            ),
+            (
+                "gbe_tx_almost_full_total",
+                "Transmit buffer almost full events",
+                c.tx_almost_full,
+            ),
+            ("gbe_rx_packets_total", "Packets received", c.rx_packets),
+            ("gbe_rx_valid_total", "Valid words received", c.rx_valid),
+            (
+                "gbe_rx_overflow_total",
+                "Receive buffer overflows",
+                c.rx_overflow,
+            ),
+            ("gbe_rx_bad_total", "Bad packets received", c.rx_bad),
+        ] {
+            counters.push(
+                Metric::new(name, help, MetricType::Counter, value as f64).label("core", core),
+            );
+        }
+    }
+    counters.sort_by(|a, b| a.name.cmp(&b.name));
+    metrics.append(&mut link);
+    metrics.append(&mut counters);
+    if exists(xadc::XADC) {
+        for (channel, value) in xadc::read_all(&config.thresholds, state).await? {
+            let (name, help) = match channel {
+                XadcChannel::Temperature => ("fpga_temperature_celsius", "FPGA die temperature"),
+                XadcChannel::VccInt => ("fpga_vccint_volts", "FPGA internal supply"),
+                XadcChannel::VccAux => ("fpga_vccaux_volts", "FPGA auxiliary supply"),
+                XadcChannel::VccBram => ("fpga_vccbram_volts", "FPGA BRAM supply"),
+            };
+            metrics.push(Metric::new(name, help, MetricType::Gauge, value));
+        }
+    }
+    if exists(&config.pps_register) {
+        metrics.push(Metric::new(
+            "pps_total",
+            "PPS edges seen by the gateware",
+            MetricType::Counter,
+            try_read_int(&config.pps_register, state).await? as f64,
+        ));
+    }
+    for register in config.registers.iter().filter(|r| exists(r)) {
+        metrics.push(
+            Metric::new(
+                "register",
+                "The value of a software register",
+                MetricType::Gauge,
+                try_read_int(register, state).await? as f64,
+            )
+            .label("name", register),
+        );
+    }
+    Ok(metrics)
+}
+
+/// Answers every HTTP request on `stream` with the latest metrics
+async fn serve(mut stream: TcpStream, metrics: Arc<RwLock<String>>) {
+    // We only care about the request line, so read just the first chunk of the request
+    let mut request = [0u8; 1024];
+    let n = match stream.read(&mut request).await {
+        Ok(n) => n,
+        Err(e) => {
+            debug!(?e, "Couldn't read HTTP request");
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&request[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let response = if path == "/metrics" || path == "/" {
+        let body = metrics.read().await.clone();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!(?e, "Couldn't write HTTP response");
+    }
+}
+
+/// Polls the board forever, serving the metrics over HTTP at `addr`
+pub async fn run(
+    addr: SocketAddr,
+    config: &MonitorConfig,
+    state: &mut State,
+) -> std::io::Result<()> {
+    let present = list_registers(state).await;
+    for wanted in config
+        .cores
+        .iter()
+        .chain(&config.registers)
+        .filter(|r| !present.contains(r))
+    {
+        warn!("{} isn't on the board, so we won't monitor it", wanted);
+    }
+    let metrics = Arc::new(RwLock::new(String::new()));
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    let served = metrics.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, served.clone()));
+                }
+                Err(e) => warn!(?e, "Couldn't accept a connection"),
+            }
+        }
+    });
+    loop {
+        let polled = timeout(POLL_TIMEOUT, poll(config, &present, state))
+            .await
+            .unwrap_or_else(|_| Err("The board stopped answering".to_owned()));
+        let polled = match polled {
+            Ok(polled) => polled,
+            Err(e) => {
+                warn!("Couldn't poll the board, reconnecting: {}", e);
+                if let Err(e) = state.reconnect(config.interval).await {
+                    warn!(?e, "Couldn't reconnect to the board");
+                }
+                vec![up(false)]
+            }
+        };
+        *metrics.write().await = render(&polled);
+        sleep(config.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = [
+            Metric::new(
+                "gbe_link_up",
+                "Whether the 10GbE link is up",
+                MetricType::Gauge,
+                1.0,
+            )
+            .label("core", "gbe0"),
+            Metric::new(
+                "gbe_link_up",
+                "Whether the 10GbE link is up",
+                MetricType::Gauge,
+                0.0,
+            )
+            .label("core", "gbe1"),
+            Metric::new("pps_total", "PPS edges", MetricType::Counter, 42.0),
+        ];
+        assert_eq!(
+            render(&metrics),
+            "# HELP snap_gbe_link_up Whether the 10GbE link is up\n\
+             # TYPE snap_gbe_link_up gauge\n\
+             snap_gbe_link_up{core=\"gbe0\"} 1\n\
+             snap_gbe_link_up{core=\"gbe1\"} 0\n\
+             # HELP snap_pps_total PPS edges\n\
+             # TYPE snap_pps_total counter\n\
+             snap_pps_total 42\n"
+        );
+        assert_eq!(
+            render(&[up(false)]),
+            "# HELP snap_up Whether we could reach the board\n\
+             # TYPE snap_up gauge\n\
+             snap_up 0\n"
+        );
+    }
+}
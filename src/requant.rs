@@ -0,0 +1,222 @@
+//! Routines for picking the requantizer's gain (bit selection) automatically
+//!
+//! We capture the requantized output with a snapshot block, work out how spread out each channel's
+//! samples are, and scale that channel's EQ coefficient until its RMS hits the target.
+//! The snapshot should be triggered on the start of a spectrum, so that word `i` after the trigger
+//! belongs to channel `i % channels`. If the snapshot is shallower than a spectrum, we need its
+//! `<name>_trig_offset` register to step through the rest of the channels.
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::{
+    eq::{read_coefficients, write_coefficients, EqFormat},
+    snapshot::{ArmOptions, FieldType, Snapshot},
+    utils::State,
+};
+
+/// The fewest samples we want from each channel before trusting its statistics
+const MIN_SAMPLES: usize = 32;
+/// The most captures we'll take to get enough samples from every channel
+const MAX_CAPTURES: usize = 64;
+/// The fraction of clipped samples past which the RMS is too underestimated to scale by
+const MAX_CLIPPING: f64 = 0.05;
+/// The widest samples we measure
+const MAX_SAMPLE_BITS: u32 = 64;
+
+/// How spread out the requantized samples of one channel are
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelStats {
+    /// In units of the output's least significant bit
+    pub rms: f64,
+    /// The fraction of samples at either end of the output's range
+    pub clipping: f64,
+}
+
+impl ChannelStats {
+    /// Computes the statistics of `samples`, each of which is `(value in LSBs, clipped)`
+    fn from_samples(samples: &[(f64, bool)]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        let n = samples.len() as f64;
+        Self {
+            rms: (samples.iter().map(|(v, _)| v * v).sum::<f64>() / n).sqrt(),
+            clipping: samples.iter().filter(|(_, c)| *c).count() as f64 / n,
+        }
+    }
+}
+
+/// The coefficient that should bring a channel with `stats` to `target` RMS
+fn next_coefficient(coeff: f64, stats: &ChannelStats, target: f64) -> f64 {
+    if stats.rms == 0.0 {
+        // We can't tell how far off we are, so just turn it up
+        coeff * 2.0
+    } else if stats.clipping > MAX_CLIPPING {
+        // Clipping hides how big the signal really is, so come down by at least half
+        coeff * (target / stats.rms).min(0.5)
+    } else {
+        coeff * target / stats.rms
+    }
+}
+
+/// Whether a sample of a signed `width`-bit field, in LSBs, sits at either end of its range
+fn is_clipped(value: f64, width: u32) -> bool {
+    // In floating point, so a full 64-bit field doesn't overflow
+    let max = 2f64.powi(width as i32 - 1);
+    value <= -max || value >= max - 1.0
+}
+
+/// Everything needed to iterate the EQ towards a target output RMS
+#[derive(Debug, Clone)]
+pub struct BitSelect {
+    /// The snapshot of the requantized output, with a signed field per sample in each word
+    pub snapshot: Snapshot,
+    pub options: ArmOptions,
+    pub timeout: Duration,
+    /// The EQ BRAM
+    pub bram: String,
+    pub channels: usize,
+    pub format: EqFormat,
+    /// The RMS to aim for, in LSBs
+    pub target: f64,
+    /// How far (as a fraction) from the target each channel may be
+    pub tolerance: f64,
+    pub iterations: usize,
+}
+
+impl BitSelect {
+    /// Checks the snapshot has signed fields of samples we can measure
+    pub fn check(&self) -> Result<(), String> {
+        let mut signed = self
+            .snapshot
+            .fields
+            .iter()
+            .filter(|f| f.ty == FieldType::Signed)
+            .peekable();
+        if signed.peek().is_none() {
+            return Err("The snapshot needs at least one signed field of samples".to_owned());
+        }
+        if let Some(field) = signed.find(|f| f.width > MAX_SAMPLE_BITS) {
+            return Err(format!(
+                "Field {} is {} bits wide, but samples are at most {} bits",
+                field.name, field.width, MAX_SAMPLE_BITS
+            ));
+        }
+        Ok(())
+    }
+
+    /// Captures the requantized output until every channel has enough samples, and computes their statistics
+    pub async fn measure(&self, state: &mut State) -> Result<Vec<ChannelStats>, String> {
+        self.check()?;
+        let mut samples = vec![vec![]; self.channels];
+        let mut offset = self.options.offset.unwrap_or(0) as usize;
+        for _ in 0..MAX_CAPTURES {
+            let options = ArmOptions {
+                offset: Some(offset as u32).filter(|&o| o != 0),
+                ..self.options
+            };
+            let capture = self.snapshot.capture(&options, self.timeout, state).await?;
+            for (field, column) in capture.fields.iter().zip(&capture.columns) {
+                if field.ty != FieldType::Signed {
+                    continue;
+                }
+                let lsb = 2f64.powi(field.bin_pt as i32);
+                for (i, value) in column.iter().enumerate() {
+                    let value = value * lsb;
+                    let clipped = is_clipped(value, field.width);
+                    samples[(offset + i) % self.channels].push((value, clipped));
+                }
+            }
+            offset += capture.len();
+            if samples.iter().all(|s| s.len() >= MIN_SAMPLES) {
+                return Ok(samples
+                    .iter()
+                    .map(|s| ChannelStats::from_samples(s))
+                    .collect());
+            }
+        }
+        Err(
+            "Couldn't capture every channel, does the snapshot have a trigger offset register?"
+                .to_owned(),
+        )
+    }
+
+    /// Scales the EQ coefficients until every channel is within tolerance of the target RMS,
+    /// returning the statistics we ended with
+    pub async fn run(&self, state: &mut State) -> Result<Vec<ChannelStats>, String> {
+        let mut coeffs = read_coefficients(&self.bram, self.channels, &self.format, state).await;
+        let mut stats = self.measure(state).await?;
+        for iteration in 0..=self.iterations {
+            let off = stats
+                .iter()
+                .filter(|s| (s.rms - self.target).abs() > self.tolerance * self.target)
+                .count();
+            info!(iteration, off, "Channels off target");
+            if off == 0 {
+                return Ok(stats);
+            }
+            if iteration == self.iterations {
+                break;
+            }
+            for (coeff, stats) in coeffs.iter_mut().zip(&stats) {
+                *coeff = next_coefficient(*coeff, stats, self.target);
+            }
+            write_coefficients(&self.bram, &coeffs, &self.format, state).await?;
+            stats = self.measure(state).await?;
+            debug!(?stats, "Measured after scaling");
+        }
+        warn!("Some channels didn't reach the target RMS, they may have no signal or be saturated");
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_select() {
+        let stats =
+            ChannelStats::from_samples(&[(3.0, false), (-3.0, false), (-8.0, true), (0.0, false)]);
+        assert_eq!(stats.rms, (82.0f64 / 4.0).sqrt());
+        assert_eq!(stats.clipping, 0.25);
+        let quiet = ChannelStats {
+            rms: 1.5,
+            clipping: 0.0,
+        };
+        assert_eq!(next_coefficient(1.0, &quiet, 3.0), 2.0);
+        let clipped = ChannelStats {
+            rms: 4.0,
+            clipping: 0.2,
+        };
+        assert_eq!(next_coefficient(1.0, &clipped, 3.0), 0.5);
+        assert_eq!(next_coefficient(1.0, &ChannelStats::default(), 3.0), 2.0);
+        let mut bit_select = BitSelect {
+            snapshot: Snapshot::new("requant_snap", vec!["re:65:0:signed".parse().unwrap()]),
+            options: ArmOptions::default(),
+            timeout: Duration::from_secs(1),
+            bram: "eq_coeffs".to_owned(),
+            channels: 2048,
+            format: EqFormat::default(),
+            target: 3.0,
+            tolerance: 0.1,
+            iterations: 8,
+        };
+        assert!(bit_select.check().is_err());
+        bit_select.snapshot.fields[0].width = 4;
+        assert!(bit_select.check().is_ok());
+    }
+
+    #[test]
+    fn test_clipping() {
+        assert!(is_clipped(7.0, 4));
+        assert!(is_clipped(-8.0, 4));
+        assert!(!is_clipped(6.0, 4));
+        assert!(!is_clipped(-7.0, 4));
+        // The widest field we measure
+        assert!(!is_clipped(0.0, 64));
+        assert!(!is_clipped(-1e15, 64));
+        assert!(is_clipped(i64::MIN as f64, 64));
+        assert!(is_clipped(i64::MAX as f64, 64));
+    }
+}
@@ -0,0 +1,377 @@
+//! An interactive shell over the katcp connection
+//!
+//! Lines starting with `?` are sent to the board as they are, and everything else is one of the
+//! friendly commands listed by `help`. Informs that arrive while we wait at the prompt (and our
+//! own log messages) are printed above it, so they don't mangle whatever is being typed.
+use std::{
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use katcp::prelude::*;
+use katcp_casper::{Base64Bytes, Read, Write};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, ExternalPrinter, Helper,
+};
+use tokio::task;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    api::{list_registers, make_raw_request},
+    utils::State,
+};
+
+const PROMPT: &str = "snap> ";
+/// The friendly commands, for completion
+const COMMANDS: [&str; 5] = ["read", "write", "registers", "help", "exit"];
+const HELP: &str = "\
+?<request> [args..]               Send a raw katcp request, like ?listdev
+read <register> [offset] [bytes]  Read bytes from a register (4 from offset 0 by default)
+write <register> <offset> <word>  Write a 32 bit word to a register
+registers                         List the registers in the running design
+help                              Show this message
+exit                              Leave the shell (as does Ctrl-D)
+Numbers can be given in decimal or with a 0x prefix.";
+
+/// Where printing goes, which is above the prompt while the shell is running and stdout otherwise
+///
+/// This is also the writer for our logs, so they end up in the same place.
+#[derive(Clone, Default)]
+pub struct SharedPrinter(Arc<Mutex<Option<Box<dyn ExternalPrinter + Send>>>>);
+
+impl SharedPrinter {
+    pub fn print(&self, msg: String) {
+        let mut printer = self.0.lock().expect("The printer lock is never poisoned");
+        match printer.as_mut() {
+            Some(printer) => {
+                if let Err(e) = printer.print(msg.clone()) {
+                    print!("{}\n{}", e, msg)
+                }
+            }
+            None => print!("{}", msg),
+        }
+    }
+
+    fn set(&self, printer: Option<Box<dyn ExternalPrinter + Send>>) {
+        *self.0.lock().expect("The printer lock is never poisoned") = printer;
+    }
+}
+
+impl io::Write for SharedPrinter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.print(String::from_utf8_lossy(buf).into_owned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MakeWriter for SharedPrinter {
+    type Writer = Self;
+
+    fn make_writer(&self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// A line typed at the prompt
+#[derive(Debug, PartialEq)]
+pub enum ShellCommand {
+    Raw(Message),
+    Read {
+        register: String,
+        offset: u32,
+        num_bytes: u32,
+    },
+    Write {
+        register: String,
+        offset: u32,
+        word: u32,
+    },
+    Registers,
+    Help,
+    Exit,
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("{} isn't a number", s))
+}
+
+impl FromStr for ShellCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with('?') {
+            let request = Message::from_str(s).map_err(|e| format!("Bad request: {:?}", e))?;
+            return Ok(Self::Raw(request));
+        }
+        let words: Vec<_> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["read", register, rest @ ..] if rest.len() <= 2 => Ok(Self::Read {
+                register: register.to_string(),
+                offset: rest.first().map_or(Ok(0), |o| parse_number(o))?,
+                num_bytes: rest.get(1).map_or(Ok(4), |n| parse_number(n))?,
+            }),
+            ["write", register, offset, word] => Ok(Self::Write {
+                register: register.to_string(),
+                offset: parse_number(offset)?,
+                word: parse_number(word)?,
+            }),
+            ["registers"] => Ok(Self::Registers),
+            ["help"] => Ok(Self::Help),
+            ["exit"] | ["quit"] => Ok(Self::Exit),
+            _ => Err(format!("Unknown command: {}, try help", s)),
+        }
+    }
+}
+
+/// Completes commands, and register names as the first argument of reads and writes
+struct ShellHelper {
+    registers: Vec<String>,
+}
+
+impl ShellHelper {
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let before: Vec<_> = line[..start].split_whitespace().collect();
+        let options: Vec<&str> = match before.as_slice() {
+            [] => COMMANDS.to_vec(),
+            ["read" | "write" | "?read" | "?write"] => {
+                self.registers.iter().map(|r| r.as_str()).collect()
+            }
+            _ => vec![],
+        };
+        (
+            start,
+            options
+                .into_iter()
+                .filter(|o| o.starts_with(word))
+                .map(|o| o.to_owned())
+                .collect(),
+        )
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(&line[..pos]))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn history_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join(".snapctl_history")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Sends `request` and prints everything that comes back
+async fn print_raw_request(request: Message, state: &mut State) {
+    match make_raw_request(state, request).await {
+        Ok(messages) => {
+            for msg in messages {
+                print!("{}", msg);
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Sends `request`, returning the reply if it was successful and printing it otherwise
+async fn checked_request<T>(request: T, state: &mut State) -> Option<T>
+where
+    T: KatcpMessage,
+{
+    let request = request
+        .to_message(None)
+        .expect("Could not serialize request to a KATCP message");
+    let reply = match make_raw_request(state, request).await {
+        Ok(mut messages) => messages.pop().expect("There's always a reply"),
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+    if reply.arguments().first().map(|s| s.as_str()) != Some("ok") {
+        print!("{}", reply);
+        return None;
+    }
+    reply.try_into().ok()
+}
+
+async fn execute(command: ShellCommand, helper: &mut ShellHelper, state: &mut State) {
+    match command {
+        ShellCommand::Raw(request) => print_raw_request(request, state).await,
+        ShellCommand::Read {
+            register,
+            offset,
+            num_bytes,
+        } => {
+            let request = Read::Request {
+                name: register,
+                offset,
+                num_bytes,
+            };
+            if let Some(Read::Reply { bytes, .. }) = checked_request(request, state).await {
+                match <[u8; 4]>::try_from(bytes.0.as_slice()) {
+                    Ok(word) => println!("{} ({})", hex(&bytes.0), u32::from_be_bytes(word)),
+                    Err(_) => println!("{}", hex(&bytes.0)),
+                }
+            }
+        }
+        ShellCommand::Write {
+            register,
+            offset,
+            word,
+        } => {
+            let request = Write::Request {
+                name: register,
+                offset,
+                bytes: Base64Bytes(word.to_be_bytes().to_vec()),
+            };
+            checked_request(request, state).await;
+        }
+        ShellCommand::Registers => {
+            helper.registers = list_registers(state).await;
+            for register in &helper.registers {
+                println!("{}", register);
+            }
+        }
+        ShellCommand::Help => println!("{}", HELP),
+        ShellCommand::Exit => unreachable!(),
+    }
+}
+
+/// Runs the shell until it's exited, printing through `printer` while at the prompt
+pub async fn run(printer: &SharedPrinter, state: &mut State) -> rustyline::Result<()> {
+    let mut editor = Editor::<ShellHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ShellHelper {
+        registers: list_registers(state).await,
+    }));
+    let history = history_path();
+    // There's no history the first time around
+    let _ = editor.load_history(&history);
+    // Without a terminal there's no prompt to print above
+    if let Ok(external) = editor.create_external_printer() {
+        printer.set(Some(Box::new(external)));
+    }
+    loop {
+        let mut prompt = task::spawn_blocking(move || {
+            let line = editor.readline(PROMPT);
+            (editor, line)
+        });
+        // Show any informs that turn up while we wait for a line
+        let line;
+        (editor, line) = loop {
+            tokio::select! {
+                done = &mut prompt => break done.expect("The prompt panicked"),
+                Some(msg) = state.unhandled_incoming_messages.recv() => printer.print(msg.to_string()),
+            }
+        };
+        let line = match line {
+            Ok(line) => line,
+            // Ctrl-C just clears the line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                printer.set(None);
+                return Err(e);
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str())?;
+        match line.parse() {
+            Ok(ShellCommand::Exit) => break,
+            Ok(command) => {
+                let helper = editor.helper_mut().expect("We set the helper");
+                execute(command, helper, state).await
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+    printer.set(None);
+    editor.save_history(&history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_command() {
+        assert_eq!(
+            "read gbe0 0x14".parse(),
+            Ok(ShellCommand::Read {
+                register: "gbe0".to_owned(),
+                offset: 0x14,
+                num_bytes: 4
+            })
+        );
+        assert_eq!(
+            "write acc_len 0 8192".parse(),
+            Ok(ShellCommand::Write {
+                register: "acc_len".to_owned(),
+                offset: 0,
+                word: 8192
+            })
+        );
+        match "?listdev size".parse() {
+            Ok(ShellCommand::Raw(msg)) => {
+                assert_eq!(msg.name(), "listdev");
+                assert_eq!(msg.arguments(), vec!["size"]);
+            }
+            other => panic!("Parsed {:?}", other),
+        }
+        assert!("read".parse::<ShellCommand>().is_err());
+        assert!("write gbe0 0 zero".parse::<ShellCommand>().is_err());
+        let helper = ShellHelper {
+            registers: vec!["gbe0".to_owned(), "gbe1".to_owned(), "acc_len".to_owned()],
+        };
+        assert_eq!(
+            helper.candidates("re"),
+            (0, vec!["read".to_owned(), "registers".to_owned()])
+        );
+        assert_eq!(
+            helper.candidates("read gb"),
+            (5, vec!["gbe0".to_owned(), "gbe1".to_owned()])
+        );
+        assert_eq!(helper.candidates("read gbe0 0"), (10, vec![]));
+    }
+}
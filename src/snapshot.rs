@@ -0,0 +1,291 @@
+//! Routines for capturing data with CASPER snapshot blocks
+//!
+//! A snapshot block named `<name>` is made up of the BRAM holding the data (`<name>_bram`),
+//! a control register (`<name>_ctrl`), a status register (`<name>_status`) and, if the block
+//! was built with one, a register holding the delay after the trigger (`<name>_trig_offset`).
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::debug;
+
+use crate::{
+    api::{has_register, read, read_int, write_int},
+    utils::State,
+};
+
+// Bits of the control register
+const CTRL_ENABLE: u32 = 0x1;
+const CTRL_MANUAL_TRIGGER: u32 = 0x2;
+const CTRL_MANUAL_VALID: u32 = 0x4;
+const CTRL_CIRCULAR: u32 = 0x8;
+
+// Bits of the status register
+const STATUS_RUNNING: u32 = 0x8000_0000;
+const STATUS_BYTES: u32 = 0x7fff_ffff;
+
+/// The widest word we unpack
+const MAX_WORD_BITS: u32 = 128;
+
+/// How often we poll the status register while waiting for a capture
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How the bits of a field should be interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Unsigned,
+    Signed,
+    Bool,
+}
+
+/// One of the fields packed into each word of a snapshot, as described by the block's metadata
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Field {
+    pub name: String,
+    pub width: u32,
+    pub bin_pt: u32,
+    pub ty: FieldType,
+}
+
+impl Field {
+    /// Interprets the `width` low bits of `raw` as this field
+    pub fn convert(&self, raw: u128) -> f64 {
+        let value = match self.ty {
+            FieldType::Bool => (raw & 1) as f64,
+            FieldType::Unsigned => raw as f64,
+            FieldType::Signed => {
+                // Sign extend from `width` bits
+                let shift = 128 - self.width;
+                (((raw << shift) as i128) >> shift) as f64
+            }
+        };
+        value / 2f64.powi(self.bin_pt as i32)
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    /// Parses a field from `name:width:bin_pt:type`, where type is one of `unsigned`, `signed`, or `bool`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split(':').collect();
+        if parts.len() != 4 {
+            return Err("Fields are described by name:width:bin_pt:type".to_owned());
+        }
+        let width = parts[1].parse().map_err(|_| "Bad field width")?;
+        let bin_pt = parts[2].parse().map_err(|_| "Bad field binary point")?;
+        let ty = match parts[3] {
+            "unsigned" | "u" => FieldType::Unsigned,
+            "signed" | "s" => FieldType::Signed,
+            "bool" | "b" => FieldType::Bool,
+            _ => return Err("Field type must be one of unsigned, signed, or bool".to_owned()),
+        };
+        if width == 0 || width > 128 || (ty == FieldType::Bool && width != 1) {
+            return Err("Bad field width".to_owned());
+        }
+        Ok(Self {
+            name: parts[0].to_owned(),
+            width,
+            bin_pt,
+            ty,
+        })
+    }
+}
+
+/// The number of bytes a word of `bits` bits takes up in a BRAM (padded to a power of two bytes)
+pub fn word_bytes(bits: u32) -> usize {
+    (bits as usize).div_ceil(8).next_power_of_two()
+}
+
+/// How to arm the snapshot block
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ArmOptions {
+    /// Trigger immediately instead of waiting on the gateware's trigger
+    pub manual_trigger: bool,
+    /// Capture every clock instead of waiting on the gateware's valid signal
+    pub manual_valid: bool,
+    /// Keep capturing (overwriting the BRAM) until the trigger, so we get data from before it
+    pub circular: bool,
+    /// The number of words to wait after the trigger before capturing (needs `<name>_trig_offset`)
+    pub offset: Option<u32>,
+}
+
+/// The data from a capture, one column per field
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub fields: Vec<Field>,
+    pub columns: Vec<Vec<f64>>,
+    /// The BRAM contents the columns were unpacked from
+    pub raw: Vec<u8>,
+    /// When we armed the block, which (with a manual trigger) is when it triggered
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Capture {
+    /// The number of samples captured
+    pub fn len(&self) -> usize {
+        self.columns.first().map_or(0, |c| c.len())
+    }
+
+    /// The number of bytes each sample took up in the BRAM
+    pub fn word_bytes(&self) -> usize {
+        word_bytes(self.fields.iter().map(|f| f.width).sum())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    /// Fields from most to least significant bits of each word
+    pub fields: Vec<Field>,
+}
+
+impl Snapshot {
+    pub fn new(name: &str, fields: Vec<Field>) -> Self {
+        Self {
+            name: name.to_owned(),
+            fields,
+        }
+    }
+
+    /// The number of bits of each word the fields take up
+    pub fn total_width(&self) -> u32 {
+        self.fields.iter().map(|f| f.width).sum()
+    }
+
+    /// The number of bytes each sample takes up in the BRAM
+    pub fn word_bytes(&self) -> usize {
+        word_bytes(self.total_width())
+    }
+
+    /// Checks the fields fit in the words we can unpack
+    pub fn check(&self) -> Result<(), String> {
+        if self.total_width() > MAX_WORD_BITS {
+            return Err(format!(
+                "The fields of {} add up to {} bits, but words are at most {} bits",
+                self.name,
+                self.total_width(),
+                MAX_WORD_BITS
+            ));
+        }
+        Ok(())
+    }
+
+    /// Arms the block so that it captures on the next trigger, returning when we armed it
+    pub async fn arm(
+        &self,
+        options: &ArmOptions,
+        state: &mut State,
+    ) -> Result<DateTime<Utc>, String> {
+        if let Some(offset) = options.offset {
+            let offset_name = format!("{}_trig_offset", self.name);
+            if !has_register(&offset_name, state).await {
+                return Err(format!(
+                    "Snapshot {} wasn't built with a trigger offset register",
+                    self.name
+                ));
+            }
+            write_int(&offset_name, offset, state).await;
+        }
+        let mut ctrl = 0;
+        if options.manual_trigger {
+            ctrl |= CTRL_MANUAL_TRIGGER;
+        }
+        if options.manual_valid {
+            ctrl |= CTRL_MANUAL_VALID;
+        }
+        if options.circular {
+            ctrl |= CTRL_CIRCULAR;
+        }
+        // The block arms on the rising edge of the enable bit
+        let ctrl_name = format!("{}_ctrl", self.name);
+        write_int(&ctrl_name, ctrl, state).await;
+        let armed = Utc::now();
+        write_int(&ctrl_name, ctrl | CTRL_ENABLE, state).await;
+        debug!(name = %self.name, ?options, "Armed snapshot");
+        Ok(armed)
+    }
+
+    /// Polls the status register until the capture is done, returning the number of bytes captured
+    pub async fn wait(&self, timeout: Duration, state: &mut State) -> Result<usize, String> {
+        let status_name = format!("{}_status", self.name);
+        let start = Instant::now();
+        loop {
+            let status = read_int(&status_name, state).await;
+            if status & STATUS_RUNNING == 0 && status & STATUS_BYTES != 0 {
+                return Ok((status & STATUS_BYTES) as usize);
+            }
+            if start.elapsed() > timeout {
+                return Err(format!("Snapshot {} never finished capturing", self.name));
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Unpacks the raw (big endian) BRAM contents of a capture armed at `timestamp`
+    pub fn unpack(&self, raw: &[u8], timestamp: DateTime<Utc>) -> Capture {
+        let total_bits = self.total_width();
+        let mut columns = vec![vec![]; self.fields.len()];
+        for word in raw.chunks_exact(self.word_bytes()) {
+            let word = word.iter().fold(0u128, |acc, &b| (acc << 8) | b as u128);
+            // The first field is in the most significant bits
+            let mut shift = total_bits;
+            for (field, column) in self.fields.iter().zip(columns.iter_mut()) {
+                shift -= field.width;
+                let mask = u128::MAX >> (128 - field.width);
+                column.push(field.convert((word >> shift) & mask));
+            }
+        }
+        Capture {
+            fields: self.fields.clone(),
+            columns,
+            raw: raw.to_vec(),
+            timestamp,
+        }
+    }
+
+    /// Arms the block, waits for it to finish, and reads back and unpacks the data
+    pub async fn capture(
+        &self,
+        options: &ArmOptions,
+        timeout: Duration,
+        state: &mut State,
+    ) -> Result<Capture, String> {
+        self.check()?;
+        let armed = self.arm(options, state).await?;
+        let num_bytes = self.wait(timeout, state).await?;
+        // Only read back whole words
+        let num_bytes = num_bytes - num_bytes % self.word_bytes();
+        let raw = read(&format!("{}_bram", self.name), 0, num_bytes as u32, state).await;
+        Ok(self.unpack(&raw, armed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack() {
+        let snap = Snapshot::new(
+            "adc_snap",
+            vec![
+                "re:8:7:signed".parse().unwrap(),
+                "im:8:0:unsigned".parse().unwrap(),
+                "valid:1:0:bool".parse().unwrap(),
+            ],
+        );
+        // 17 bits pad out to a 32 bit word
+        assert_eq!(snap.word_bytes(), 4);
+        // re = -64/128, im = 3, valid = 1
+        let word: u32 = (0xc0 << 9) | (3 << 1) | 1;
+        let capture = snap.unpack(&word.to_be_bytes(), Utc::now());
+        assert_eq!(capture.len(), 1);
+        assert_eq!(capture.columns, vec![vec![-0.5], vec![3.0], vec![1.0]]);
+        assert!("re:8:7".parse::<Field>().is_err());
+        let wide = Snapshot::new("wide", vec!["data:64:0:unsigned".parse().unwrap(); 3]);
+        assert!(wide.check().is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Routines for reading accumulated power spectra out of the spectrometer's BRAMs
+//!
+//! Each polarisation's spectrum is spread across one or more BRAMs, which CASPER designs often
+//! interleave so that BRAM `i` of `n` holds channels `i`, `i + n`, `i + 2n`, and so on. The
+//! accumulation counter increments every time a new spectrum is dumped into the BRAMs.
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::{
+    api::{read, read_int, write_int},
+    snapshot::{word_bytes, Field},
+    utils::State,
+};
+
+/// The register holding the number of spectra to accumulate
+pub const ACC_LEN_REGISTER: &str = "acc_len";
+/// The register counting the accumulations dumped so far
+pub const ACC_CNT_REGISTER: &str = "acc_cnt";
+/// The number of channels in the spectra, unless we're told otherwise
+pub const DEFAULT_CHANNELS: usize = 2048;
+/// The default format of each accumulated channel
+pub const DEFAULT_FIELD: &str = "power:64:0:unsigned";
+
+/// How often we poll the accumulation counter while waiting for a new spectrum
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How many times we try to read a spectrum before giving up on it being torn
+const MAX_TRIES: usize = 5;
+
+/// The names of the `interleave` BRAMs that make up the spectrum of `prefix`
+///
+/// A single BRAM is just `prefix`, a pair is `prefix_even` and `prefix_odd`, and anything
+/// else is `prefix_0`, `prefix_1`, and so on.
+pub fn bram_names(prefix: &str, interleave: usize) -> Vec<String> {
+    match interleave {
+        1 => vec![prefix.to_owned()],
+        2 => vec![format!("{}_even", prefix), format!("{}_odd", prefix)],
+        n => (0..n).map(|i| format!("{}_{}", prefix, i)).collect(),
+    }
+}
+
+/// How far below the strongest channel we bother plotting, in dB
+const PLOT_RANGE: f64 = 80.0;
+
+/// Converts powers to dB, treating anything below the smallest accumulation as the smallest
+pub fn to_db(powers: &[f64]) -> Vec<f64> {
+    powers.iter().map(|p| 10.0 * p.max(1e-12).log10()).collect()
+}
+
+/// The channels of the `n` strongest local maxima, strongest first
+pub fn find_peaks(db: &[f64], n: usize) -> Vec<usize> {
+    let mut peaks: Vec<_> = (0..db.len())
+        .filter(|&i| (i == 0 || db[i] > db[i - 1]) && (i + 1 == db.len() || db[i] >= db[i + 1]))
+        .collect();
+    peaks.sort_by(|a, b| db[*b].total_cmp(&db[*a]));
+    peaks.truncate(n);
+    peaks
+}
+
+/// Draws `db` as a `width` by `height` bar plot with a dB axis, a frequency axis from `freqs` (MHz),
+/// and a marker over each of `peaks`
+pub fn render_plot(
+    db: &[f64],
+    freqs: &[f64],
+    peaks: &[usize],
+    width: usize,
+    height: usize,
+) -> Vec<String> {
+    let width = width.min(db.len()).max(1);
+    // Each column shows the strongest of the channels it covers
+    let column = |channel: usize| channel * width / db.len();
+    let mut columns = vec![f64::NEG_INFINITY; width];
+    for (channel, &value) in db.iter().enumerate() {
+        let col = &mut columns[column(channel)];
+        *col = col.max(value);
+    }
+    let top = columns.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let bottom = columns
+        .iter()
+        .cloned()
+        .fold(f64::INFINITY, f64::min)
+        .max(top - PLOT_RANGE);
+    let span = (top - bottom).max(1e-9);
+    let mut lines = vec![];
+    let mut markers = vec![' '; width];
+    for &peak in peaks {
+        markers[column(peak)] = 'v';
+    }
+    lines.push(format!("{:>8} {}", "", markers.iter().collect::<String>()));
+    for row in 0..height {
+        // The level at the bottom of this row
+        let level = top - span * (row + 1) as f64 / height as f64;
+        let bars: String = columns
+            .iter()
+            .map(|&v| if v >= level { '█' } else { ' ' })
+            .collect();
+        let label = if row == 0 || row + 1 == height || row == height / 2 {
+            format!("{:>5.1} dB", level)
+        } else {
+            String::new()
+        };
+        lines.push(format!("{:>8}|{}", label, bars));
+    }
+    lines.push(format!("{:>8}+{}", "", "-".repeat(width)));
+    let first = format!("{:.1}", freqs.first().cloned().unwrap_or_default());
+    let last = format!("{:.1} MHz", freqs.last().cloned().unwrap_or_default());
+    lines.push(format!(
+        "{:>8} {}{:>pad$}",
+        "",
+        first,
+        last,
+        pad = width.saturating_sub(first.len())
+    ));
+    lines
+}
+
+/// One accumulated spectrum of every polarisation
+#[derive(Debug, Clone)]
+pub struct Spectrum {
+    /// The accumulation these spectra came from
+    pub acc_cnt: u32,
+    /// The power in every channel, per polarisation
+    pub pols: Vec<Vec<f64>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Spectrometer {
+    /// The BRAMs making up each polarisation, interleaved by channel
+    pub pols: Vec<Vec<String>>,
+    pub channels: usize,
+    /// The format of each channel's accumulated power
+    pub field: Field,
+}
+
+impl Spectrometer {
+    pub fn new(pols: Vec<Vec<String>>, channels: usize, field: Field) -> Self {
+        Self {
+            pols,
+            channels,
+            field,
+        }
+    }
+
+    /// The center frequency of every channel in MHz, for a real-sampled first Nyquist zone at `sample_rate` MHz
+    pub fn frequencies(&self, sample_rate: f64) -> Vec<f64> {
+        let width = sample_rate / 2.0 / self.channels as f64;
+        (0..self.channels).map(|i| i as f64 * width).collect()
+    }
+
+    pub async fn set_acc_len(&self, acc_len: u32, state: &mut State) {
+        write_int(ACC_LEN_REGISTER, acc_len, state).await;
+    }
+
+    pub async fn acc_cnt(&self, state: &mut State) -> u32 {
+        read_int(ACC_CNT_REGISTER, state).await
+    }
+
+    /// Puts the words read from interleaved `brams` back in channel order
+    fn deinterleave(&self, brams: &[Vec<u8>]) -> Vec<f64> {
+        let word_bytes = word_bytes(self.field.width);
+        let mask = u128::MAX >> (128 - self.field.width);
+        (0..self.channels)
+            .map(|channel| {
+                let bram = &brams[channel % brams.len()];
+                let start = channel / brams.len() * word_bytes;
+                let word = bram[start..start + word_bytes]
+                    .iter()
+                    .fold(0u128, |acc, &b| (acc << 8) | b as u128);
+                self.field.convert(word & mask)
+            })
+            .collect()
+    }
+
+    /// Reads whatever spectrum is in the BRAMs, retrying if an accumulation landed mid-read
+    pub async fn read(&self, state: &mut State) -> Result<Spectrum, String> {
+        let word_bytes = word_bytes(self.field.width);
+        for _ in 0..MAX_TRIES {
+            let acc_cnt = self.acc_cnt(state).await;
+            let mut pols = vec![];
+            for brams in &self.pols {
+                let per_bram = self.channels.div_ceil(brams.len());
+                let mut raw = vec![];
+                for bram in brams {
+                    raw.push(read(bram, 0, (per_bram * word_bytes) as u32, state).await);
+                }
+                pols.push(self.deinterleave(&raw));
+            }
+            if self.acc_cnt(state).await == acc_cnt {
+                return Ok(Spectrum { acc_cnt, pols });
+            }
+            debug!(acc_cnt, "An accumulation landed while we were reading");
+        }
+        warn!("Every read of the spectrum was torn, is the accumulation length too short?");
+        Err("Couldn't read a whole spectrum".to_owned())
+    }
+
+    /// Waits for the next accumulation to land and reads it, so we have as long as possible before the next one
+    pub async fn read_next(
+        &self,
+        timeout: Duration,
+        state: &mut State,
+    ) -> Result<Spectrum, String> {
+        let last = self.acc_cnt(state).await;
+        let start = Instant::now();
+        while self.acc_cnt(state).await == last {
+            if start.elapsed() > timeout {
+                return Err("No new accumulation arrived, is the spectrometer running?".to_owned());
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        self.read(state).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deinterleave() {
+        let spec = Spectrometer::new(
+            vec![bram_names("spec0", 2)],
+            4,
+            "power:32:0:unsigned".parse().unwrap(),
+        );
+        assert_eq!(spec.pols[0], vec!["spec0_even", "spec0_odd"]);
+        let even = [0u32, 2].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let odd = [1u32, 3].iter().flat_map(|v| v.to_be_bytes()).collect();
+        assert_eq!(spec.deinterleave(&[even, odd]), vec![0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(spec.frequencies(500.0), vec![0.0, 62.5, 125.0, 187.5]);
+    }
+
+    #[test]
+    fn test_plot() {
+        let db = to_db(&[1.0, 10.0, 1.0, 1.0, 1000.0, 100.0, 1.0, 0.0]);
+        assert_eq!(db[1], 10.0);
+        assert_eq!(find_peaks(&db, 5), vec![4, 1]);
+        let freqs: Vec<_> = (0..8).map(|i| i as f64).collect();
+        let lines = render_plot(&db, &freqs, &[4], 8, 4);
+        assert_eq!(lines.len(), 4 + 3);
+        assert_eq!(lines[0].trim(), "v");
+        // The plot spans 80 dB, so the top row covers the 20 dB below the peak
+        assert_eq!(lines[1].chars().filter(|&c| c == '█').count(), 3);
+    }
+}
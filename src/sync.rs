@@ -0,0 +1,245 @@
+//! Routines for arming the pipeline on a PPS edge, so that packet sequence numbers map to UTC
+//!
+//! The gateware arms on the first PPS after a rising edge of the arm register, and counts every
+//! PPS it sees in the PPS counter register. As we can't read back exactly which PPS it armed on,
+//! we write the arm register in the middle of a second (by the host's clock, which should be
+//! disciplined by NTP or better) so that the next edge is unambiguous.
+use std::{fs, io, path::Path};
+
+use chrono::{DateTime, Duration as ChronoDuration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, warn};
+
+use crate::{
+    api::{read_int, write_int},
+    utils::State,
+};
+
+/// The register whose rising edge arms the pipeline on the next PPS
+pub const ARM_REGISTER: &str = "arm";
+/// The register counting every PPS the gateware has seen
+pub const PPS_COUNT_REGISTER: &str = "pps_cnt";
+/// The register holding the number of FPGA clocks between the last two PPS edges
+pub const CLOCKS_PER_PPS_REGISTER: &str = "pps_clks";
+/// Where we record the epoch if we're not told otherwise
+pub const DEFAULT_EPOCH_FILE: &str = "epoch.json";
+
+/// The UTC time of the PPS edge the pipeline was armed on, and how to get from sequence numbers to time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Epoch {
+    /// The PPS edge where the sequence number was zero
+    pub armed_at: DateTime<Utc>,
+    /// The ADC sample rate, in Hz
+    pub sample_rate: f64,
+    /// How many ADC samples make up each increment of the sequence number
+    pub samples_per_count: u64,
+}
+
+impl Epoch {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("The epoch always serializes");
+        fs::write(path, json)
+    }
+}
+
+/// How long to wait from `now` until the middle of a second
+fn until_mid_second(now: DateTime<Utc>) -> Duration {
+    let into_second = now.timestamp_subsec_nanos() as u64;
+    Duration::from_nanos((1_500_000_000 - into_second) % 1_000_000_000)
+}
+
+/// Arms the pipeline on the next PPS, confirms the PPS arrived, and returns the UTC time of that edge
+pub async fn arm(
+    arm_register: &str,
+    pps_register: &str,
+    state: &mut State,
+) -> Result<DateTime<Utc>, String> {
+    sleep(until_mid_second(Utc::now())).await;
+    let read_at = Utc::now();
+    let pps_before = read_int(pps_register, state).await;
+    // The arm is on the rising edge
+    write_int(arm_register, 0, state).await;
+    write_int(arm_register, 1, state).await;
+    let written = Utc::now();
+    write_int(arm_register, 0, state).await;
+    // If an edge came between the count and the arm, the count wouldn't tell us which edge we armed on
+    let second = |t: DateTime<Utc>| {
+        t.duration_trunc(ChronoDuration::seconds(1))
+            .expect("Truncating to a second always works")
+    };
+    if second(read_at) != second(written) {
+        return Err("We took too long to arm, so the edge we armed on is ambiguous".to_owned());
+    }
+    // The next edge is the whole second after we wrote the register
+    let armed_at = second(written) + ChronoDuration::seconds(1);
+    // Check again in the middle of the next second, well clear of the edge
+    sleep(until_mid_second(Utc::now())).await;
+    let pps_after = read_int(pps_register, state).await;
+    debug!(pps_before, pps_after, %armed_at, "Armed");
+    if Utc::now() - armed_at >= ChronoDuration::seconds(1) {
+        return Err("We took too long to read back the PPS count to confirm the arm".to_owned());
+    }
+    match pps_after.wrapping_sub(pps_before) {
+        0 => Err("No PPS arrived after arming, is the PPS connected?".to_owned()),
+        1 => Ok(armed_at),
+        n => Err(format!(
+            "Saw {} PPS edges in one second, the PPS isn't clean",
+            n
+        )),
+    }
+}
+
+/// The health of the PPS, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PpsStatus {
+    Healthy,
+    /// The clocks per PPS alternate between two values, so the clock isn't an integer multiple of the PPS
+    NonInteger,
+    /// The clocks per PPS wander, or there were extra pulses
+    Jitter,
+    /// Some seconds went by without a pulse
+    Missing,
+}
+
+/// What we saw of the PPS over a number of seconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PpsReport {
+    pub status: PpsStatus,
+    pub seconds: usize,
+    pub missing: u32,
+    pub extra: u32,
+    pub min_clocks: u32,
+    pub max_clocks: u32,
+}
+
+/// Assesses the PPS from `(pps count, clocks per PPS)` samples taken a second apart
+fn assess_pps(samples: &[(u32, u32)]) -> PpsReport {
+    let mut missing = 0;
+    let mut extra = 0;
+    for pair in samples.windows(2) {
+        match pair[1].0.wrapping_sub(pair[0].0) {
+            0 => missing += 1,
+            1 => (),
+            n => extra += n - 1,
+        }
+    }
+    let min_clocks = samples.iter().map(|s| s.1).min().unwrap_or_default();
+    let max_clocks = samples.iter().map(|s| s.1).max().unwrap_or_default();
+    let status = if missing > 0 {
+        PpsStatus::Missing
+    } else if extra > 0 || max_clocks - min_clocks > 1 {
+        PpsStatus::Jitter
+    } else if max_clocks != min_clocks {
+        PpsStatus::NonInteger
+    } else {
+        PpsStatus::Healthy
+    };
+    PpsReport {
+        status,
+        seconds: samples.len().saturating_sub(1),
+        missing,
+        extra,
+        min_clocks,
+        max_clocks,
+    }
+}
+
+/// Watches the PPS counter and clocks per PPS once a second for `seconds`, warning about anything amiss
+pub async fn check_pps(
+    pps_register: &str,
+    clocks_register: &str,
+    seconds: usize,
+    state: &mut State,
+) -> PpsReport {
+    let mut samples = vec![];
+    for _ in 0..=seconds {
+        // Sample mid-second so we never race the edge
+        sleep(until_mid_second(Utc::now())).await;
+        let sample = (
+            read_int(pps_register, state).await,
+            read_int(clocks_register, state).await,
+        );
+        debug!(pps = sample.0, clocks = sample.1, "Sampled PPS");
+        samples.push(sample);
+    }
+    let report = assess_pps(&samples);
+    if report.missing > 0 {
+        warn!(
+            "Missed {} PPS edges in {} seconds",
+            report.missing, report.seconds
+        );
+    }
+    if report.extra > 0 {
+        warn!(
+            "Saw {} extra PPS edges in {} seconds",
+            report.extra, report.seconds
+        );
+    }
+    match report.max_clocks - report.min_clocks {
+        0 => (),
+        1 => warn!(
+            "The clocks per PPS alternate between {} and {}, the clock isn't an integer multiple of the PPS",
+            report.min_clocks, report.max_clocks
+        ),
+        _ => warn!(
+            "The clocks per PPS wandered between {} and {}",
+            report.min_clocks, report.max_clocks
+        ),
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// The UTC time of the start of sequence number `seq`, as whatever reads the epoch works it out
+    fn time(epoch: &Epoch, seq: u64) -> DateTime<Utc> {
+        let seconds = seq as f64 * epoch.samples_per_count as f64 / epoch.sample_rate;
+        epoch.armed_at + ChronoDuration::nanoseconds((seconds * 1e9).round() as i64)
+    }
+
+    /// Reads back an epoch the way whatever consumes it would
+    fn load(path: &Path) -> Epoch {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_epoch() {
+        let armed_at = Utc.ymd(2022, 6, 1).and_hms(12, 0, 0);
+        let epoch = Epoch {
+            armed_at,
+            sample_rate: 500e6,
+            samples_per_count: 8192,
+        };
+        assert_eq!(time(&epoch, 0), armed_at);
+        // 8192 samples at 500 MHz is 16.384 us
+        assert_eq!(
+            time(&epoch, 1_000_000),
+            armed_at + ChronoDuration::milliseconds(16384)
+        );
+        let path = std::env::temp_dir().join("snapctl-test-epoch.json");
+        epoch.save(&path).unwrap();
+        assert_eq!(load(&path), epoch);
+        fs::remove_file(path).unwrap();
+        let now = armed_at + ChronoDuration::milliseconds(700);
+        assert_eq!(until_mid_second(now), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn test_assess_pps() {
+        let healthy = [(10, 250_000_000), (11, 250_000_000), (12, 250_000_000)];
+        assert_eq!(assess_pps(&healthy).status, PpsStatus::Healthy);
+        let non_integer = [(10, 250_000_000), (11, 250_000_001), (12, 250_000_000)];
+        assert_eq!(assess_pps(&non_integer).status, PpsStatus::NonInteger);
+        let jitter = [(10, 250_000_000), (11, 250_000_040), (12, 250_000_000)];
+        assert_eq!(assess_pps(&jitter).status, PpsStatus::Jitter);
+        let missing = [(u32::MAX, 250_000_000), (0, 250_000_000), (0, 250_000_000)];
+        let report = assess_pps(&missing);
+        assert_eq!(report.status, PpsStatus::Missing);
+        assert_eq!((report.seconds, report.missing, report.extra), (2, 1, 0));
+    }
+}
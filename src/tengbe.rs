@@ -4,6 +4,7 @@ use packed_struct::PackedStruct;
 use packed_struct::PackingResult;
 
 use crate::{register_address, utils::RegisterAddress};
+use serde::Deserialize;
 use std::net::Ipv4Addr;
 // The details of the memory map here are magical and come from Jack H
 
@@ -22,7 +23,10 @@ enum CoreAddress {
     IpAddress = 0x14,
     GatewayAddress = 0x18,
     Netmask = 0x1C,
+    // We don't use multicast, but keep the whole memory map
+    #[allow(dead_code)]
     MulticastIp = 0x20,
+    #[allow(dead_code)]
     MulticastMask = 0x24,
     BytesAvailable = 0x28,
     PromiscRstEn = 0x2C,
@@ -30,18 +34,8 @@ enum CoreAddress {
     Status = 0x34,
     // Control = 0x3C,
     // ARPSize = 0x44,
-    // TXPacketRate = 0x48,
-    // TXPacketCounter = 0x4C,
-    // TXValidRate = 0x50,
-    // TXValidCounter = 0x54,
-    // TXOverflowCounter = 0x58,
-    // TXAlmostFullCounter = 0x5C,
-    // RXPacketRate = 0x60,
-    // RXPacketCounter = 0x64,
-    // RXValidRate = 0x68,
-    // RXValidCounter = 0x6C,
-    // RXOverflowCounter = 0x70,
-    // RXBadCounter = 0x74,
+    // The rates and counters from 0x48 to 0x74 are read as one block
+    Counters = 0x48,
     //  CounterReset = 0x78,
 }
 
@@ -67,6 +61,7 @@ register_address! {CoreAddress,BytesAvailable}
 register_address! {CoreAddress,PromiscRstEn}
 register_address! {CoreAddress,Port}
 register_address! {CoreAddress,Status}
+register_address! {CoreAddress,Counters}
 
 #[derive(PackedStruct, Debug)]
 #[packed_struct(bit_numbering = "lsb0", size_bytes = "4")]
@@ -118,7 +113,8 @@ impl PackedStruct for MacAddress {
 }
 
 macro_rules! ip_register {
-    ($name:ident) => {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
         #[derive(Debug)]
         pub struct $name(pub Ipv4Addr);
 
@@ -139,8 +135,14 @@ macro_rules! ip_register {
 ip_register!(IpAddress);
 ip_register!(GatewayAddress);
 ip_register!(Netmask);
-ip_register!(MulticastIp);
-ip_register!(MulticastMask);
+ip_register!(
+    #[allow(dead_code)]
+    MulticastIp
+);
+ip_register!(
+    #[allow(dead_code)]
+    MulticastMask
+);
 
 #[derive(PackedStruct, Debug)]
 pub struct BytesAvailable {
@@ -176,3 +178,53 @@ pub struct Status {
     #[packed_field(bits = "0")]
     pub link_up: bool,
 }
+
+#[derive(PackedStruct, Debug, Default)]
+pub struct Counters {
+    #[packed_field(endian = "msb")]
+    pub tx_packet_rate: u32,
+    #[packed_field(endian = "msb")]
+    pub tx_packets: u32,
+    #[packed_field(endian = "msb")]
+    pub tx_valid_rate: u32,
+    #[packed_field(endian = "msb")]
+    pub tx_valid: u32,
+    #[packed_field(endian = "msb")]
+    pub tx_overflow: u32,
+    #[packed_field(endian = "msb")]
+    pub tx_almost_full: u32,
+    #[packed_field(endian = "msb")]
+    pub rx_packet_rate: u32,
+    #[packed_field(endian = "msb")]
+    pub rx_packets: u32,
+    #[packed_field(endian = "msb")]
+    pub rx_valid_rate: u32,
+    #[packed_field(endian = "msb")]
+    pub rx_valid: u32,
+    #[packed_field(endian = "msb")]
+    pub rx_overflow: u32,
+    #[packed_field(endian = "msb")]
+    pub rx_bad: u32,
+}
+
+/// The registers next to the core holding where it sends packets
+pub const DEST_IP_REGISTER: &str = "dest_ip";
+pub const DEST_PORT_REGISTER: &str = "dest_port";
+
+/// Everything we set up on a core, along with where it streams to
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GbeSettings {
+    /// The core's MAC address, or whatever it already has if `None`
+    pub mac: Option<mac_address::MacAddress>,
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    #[serde(default = "default_netmask")]
+    pub netmask: Ipv4Addr,
+    pub port: u16,
+    pub dest_ip: Ipv4Addr,
+    pub dest_port: u16,
+}
+
+fn default_netmask() -> Ipv4Addr {
+    Ipv4Addr::new(255, 255, 255, 0)
+}
@@ -1,7 +1,24 @@
-use std::net::IpAddr;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
 
-use crate::Message;
-use tokio::{net::tcp::OwnedWriteHalf, sync::mpsc::UnboundedReceiver};
+use crate::{
+    adc::Shadow,
+    handlers::{handle_informs, make_inform_dispatchers},
+    Message,
+};
+use katcp_casper::FpgaStatus;
+use tokio::{
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver},
+        watch,
+    },
+    task,
+    time::{sleep, timeout, Duration, Instant},
+};
+use tracing::debug;
 
 pub trait RegisterAddress {
     /// Returns the address of this particular struct
@@ -22,8 +39,94 @@ macro_rules! register_address {
 
 pub struct State {
     pub unhandled_incoming_messages: UnboundedReceiver<Message>,
-    // The writer
-    pub writer: OwnedWriteHalf,
+    // The writer, or None if we're only printing what we'd send (--dry-run)
+    pub writer: Option<OwnedWriteHalf>,
     // The connection address
     pub address: IpAddr,
+    // The katcp port we connected to
+    pub port: u16,
+    // The informs the server sent when we connected
+    pub greeting: Vec<Message>,
+    // The last FPGA status the server told us about with #fpga
+    pub fpga_status: watch::Receiver<Option<FpgaStatus>>,
+    // What we've written to the (write-only) ADC registers
+    pub adc: Shadow,
+}
+
+impl State {
+    /// Connects to the katcp server at `address` and `port`, dispatching the informs we handle
+    pub async fn connect(address: IpAddr, port: u16) -> io::Result<Self> {
+        let mut state = Self::dry(address, port);
+        state.open().await?;
+        Ok(state)
+    }
+
+    /// A state that's never connected, for --dry-run
+    pub fn dry(address: IpAddr, port: u16) -> Self {
+        let (_, rx) = unbounded_channel();
+        Self {
+            unhandled_incoming_messages: rx,
+            writer: None,
+            address,
+            port,
+            greeting: vec![],
+            fpga_status: watch::channel(None).1,
+            adc: Shadow::default(),
+        }
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        let (reader, writer) = TcpStream::connect(SocketAddr::new(self.address, self.port))
+            .await?
+            .into_split();
+        let (tx, rx) = unbounded_channel();
+        let (fpga_tx, fpga_rx) = watch::channel(None);
+        task::spawn(handle_informs(tx, reader, make_inform_dispatchers(fpga_tx)));
+        self.unhandled_incoming_messages = rx;
+        self.fpga_status = fpga_rx;
+        self.writer = Some(writer);
+        self.greeting = vec![];
+        Ok(())
+    }
+
+    /// Waits up to `wait` for the server to close the connection, returning whether it did
+    pub async fn wait_for_disconnect(&mut self, wait: Duration) -> bool {
+        if self.dry_run() {
+            return true;
+        }
+        timeout(wait, async {
+            // The channel closes once the reader sees the end of the connection
+            while let Some(msg) = self.unhandled_incoming_messages.recv().await {
+                debug!(
+                    ?msg,
+                    "Unhandled message while waiting for the server to go away"
+                );
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Tries to connect again until `wait` has passed, for when the server has restarted
+    pub async fn reconnect(&mut self, wait: Duration) -> io::Result<()> {
+        let start = Instant::now();
+        loop {
+            // A connect to a host that's still down can hang well past `wait` on its own
+            let attempt = timeout(wait.saturating_sub(start.elapsed()), self.open())
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            match attempt {
+                Ok(()) => return Ok(()),
+                Err(e) if start.elapsed() > wait => return Err(e),
+                Err(e) => {
+                    debug!(?e, "The server isn't back yet");
+                    sleep(Duration::from_millis(500)).await
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,139 @@
+//! Routines for reading the FPGA's system monitor (XADC) through the CASPER sysmon block
+//!
+//! The sysmon block maps each of the XADC's DRP registers to a 32 bit word, where the top 12 bits
+//! of the low 16 are the latest ADC code of that channel.
+use tracing::warn;
+
+use crate::{api::try_read, utils::State};
+
+/// The name of the wishbone block exposing the XADC
+pub const XADC: &str = "xadc";
+/// The nominal supply voltages of the Kintex-7
+const VCCINT: f64 = 1.0;
+const VCCAUX: f64 = 1.8;
+const VCCBRAM: f64 = 1.0;
+
+/// The channels of the XADC we care about, with their DRP addresses
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum XadcChannel {
+    Temperature = 0x0,
+    VccInt = 0x1,
+    VccAux = 0x2,
+    VccBram = 0x6,
+}
+
+impl XadcChannel {
+    pub const ALL: [XadcChannel; 4] = [
+        XadcChannel::Temperature,
+        XadcChannel::VccInt,
+        XadcChannel::VccAux,
+        XadcChannel::VccBram,
+    ];
+
+    /// Converts the raw DRP register into °C or V
+    pub fn convert(&self, raw: u32) -> f64 {
+        let code = ((raw & 0xffff) >> 4) as f64;
+        match self {
+            XadcChannel::Temperature => code * 503.975 / 4096.0 - 273.15,
+            _ => code / 4096.0 * 3.0,
+        }
+    }
+
+    pub fn units(&self) -> &'static str {
+        match self {
+            XadcChannel::Temperature => "°C",
+            _ => "V",
+        }
+    }
+}
+
+/// The limits outside of which we warn about the FPGA's health
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// The hottest the die should get, in °C
+    pub max_temperature: f64,
+    /// How far (as a fraction) each supply may be from nominal
+    pub supply_tolerance: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_temperature: 85.0,
+            supply_tolerance: 0.05,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Checks `value` from `channel`, returning why it's out of limits if it is
+    pub fn check(&self, channel: XadcChannel, value: f64) -> Result<(), String> {
+        let nominal = match channel {
+            XadcChannel::Temperature => {
+                return if value > self.max_temperature {
+                    Err(format!(
+                        "The FPGA is at {:.1} °C, above {:.1} °C",
+                        value, self.max_temperature
+                    ))
+                } else {
+                    Ok(())
+                };
+            }
+            XadcChannel::VccInt => VCCINT,
+            XadcChannel::VccAux => VCCAUX,
+            XadcChannel::VccBram => VCCBRAM,
+        };
+        if (value - nominal).abs() > nominal * self.supply_tolerance {
+            Err(format!(
+                "{:?} is at {:.3} V, more than {:.0}% from {} V",
+                channel,
+                value,
+                self.supply_tolerance * 100.0,
+                nominal
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reads the latest value of `channel`, in °C or V
+pub async fn read_channel(channel: XadcChannel, state: &mut State) -> Result<f64, String> {
+    let bytes = try_read(XADC, channel as u32 * 4, 4, state).await?;
+    Ok(channel.convert(u32::from_be_bytes(
+        bytes.try_into().expect("We already read 4 bytes"),
+    )))
+}
+
+/// Reads every channel, warning about any outside of `thresholds`
+pub async fn read_all(
+    thresholds: &Thresholds,
+    state: &mut State,
+) -> Result<Vec<(XadcChannel, f64)>, String> {
+    let mut values = vec![];
+    for channel in XadcChannel::ALL {
+        let value = read_channel(channel, state).await?;
+        if let Err(e) = thresholds.check(channel, value) {
+            warn!("{}", e);
+        }
+        values.push((channel, value));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xadc() {
+        assert!((XadcChannel::Temperature.convert(0x9b60) - 32.73).abs() < 0.01);
+        assert!((XadcChannel::VccInt.convert(0x5550) - 1.0).abs() < 0.001);
+        let thresholds = Thresholds::default();
+        assert!(thresholds.check(XadcChannel::Temperature, 60.0).is_ok());
+        assert!(thresholds.check(XadcChannel::Temperature, 90.0).is_err());
+        assert!(thresholds.check(XadcChannel::VccAux, 1.75).is_ok());
+        assert!(thresholds.check(XadcChannel::VccBram, 0.9).is_err());
+    }
+}